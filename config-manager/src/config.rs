@@ -8,14 +8,27 @@ use serde_json::Value;
 use tokio::fs;
 use tracing::{debug, error};
 
+/// hash of the acl, set by the operator
+pub const POLICY_HASH_ANNOTATION: &str = "headscale.juliamertz.dev/policy-hash";
+/// hash of the acl headscale was last signaled to reload
+pub const SYNCED_HASH_ANNOTATION: &str = "headscale.juliamertz.dev/synced-hash";
+/// error of the last reload, if headscale couldn't be signaled
+pub const SYNC_ERROR_ANNOTATION: &str = "headscale.juliamertz.dev/sync-error";
+
 pub struct Config {
     pub acls: Value,
+    pub hash: Option<String>,
+    pub synced_hash: Option<String>,
 }
 
 impl TryFrom<ConfigMap> for Config {
     type Error = Error;
 
     fn try_from(configmap: ConfigMap) -> Result<Self, Self::Error> {
+        let annotations = configmap.metadata.annotations.unwrap_or_default();
+        let hash = annotations.get(POLICY_HASH_ANNOTATION).cloned();
+        let synced_hash = annotations.get(SYNCED_HASH_ANNOTATION).cloned();
+
         let data = configmap.data.unwrap_or_default();
         let content = data.get("acl.json").map(String::as_str).unwrap_or("{}");
 
@@ -30,7 +43,11 @@ impl TryFrom<ConfigMap> for Config {
             }
         };
 
-        Ok(Self { acls })
+        Ok(Self {
+            acls,
+            hash,
+            synced_hash,
+        })
     }
 }
 
@@ -8,13 +8,15 @@ use std::{error::Error as StdError, time::Duration};
 use clap::{Parser, Subcommand};
 use futures::{StreamExt, TryStreamExt};
 use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::{Patch, PatchParams};
 use kube::runtime::WatchStreamExt;
 use kube::{Api, Client};
+use serde_json::json;
 use tokio::time::sleep;
 use tracing::{debug, error, info};
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::config::{Config, ConfigManager};
+use crate::config::{Config, ConfigManager, SYNC_ERROR_ANNOTATION, SYNCED_HASH_ANNOTATION};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -85,15 +87,59 @@ async fn init(ctx: Context) -> Result<()> {
     Ok(())
 }
 
+/// records the outcome of a reload on the configmap, so the operator can report it
+async fn confirm(ctx: &Context, hash: &str, error: Option<String>) -> Result<()> {
+    let patch = json!({
+        "metadata": {
+            "annotations": {
+                SYNCED_HASH_ANNOTATION: hash,
+                SYNC_ERROR_ANNOTATION: error,
+            }
+        }
+    });
+    ctx.api
+        .patch(
+            &ctx.opts.configmap_name,
+            &PatchParams::default(),
+            &Patch::Merge(patch),
+        )
+        .await?;
+
+    Ok(())
+}
+
 async fn handle_event(ctx: &Context, configmap: ConfigMap) -> Result<()> {
     let config = Config::try_from(configmap)?;
     let changed = ctx.manager.sync(&config.acls).await?;
 
+    // the operator has headscale check the acl before it is written, so it only has to be loaded.
+    // the reload can't be observed from here, the operator reports acls it couldn't check as
+    // unverified
     if changed {
-        let headscale_process = find_headscale_proc()?;
-        headscale_process.sighup()?;
-        info!("sent SIGHUP to headscale container");
+        let signaled = find_headscale_proc()
+            .map_err(Error::from)
+            .and_then(|process| Ok(process.sighup()?));
+        let error = match signaled {
+            Ok(()) => {
+                info!("sent SIGHUP to headscale container");
+                None
+            }
+            Err(err) => Some(format!("unable to signal headscale: {err}")),
+        };
+        if let Some(ref error) = error {
+            error!(error, "headscale failed to reload the acl");
+        }
+
+        if let Some(ref hash) = config.hash {
+            confirm(ctx, hash, error).await?;
+        }
+    } else if let Some(ref hash) = config.hash
+        && config.synced_hash.as_ref() != Some(hash)
+    {
+        // the file is already up to date, headscale loaded it when it started
+        confirm(ctx, hash, None).await?;
     }
+
     Ok(())
 }
 
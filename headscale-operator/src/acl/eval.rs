@@ -0,0 +1,398 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use crate::acl::validate::{FieldError, parse_cidr, parse_port, parse_port_range};
+use crate::crds::policy::{Action, PolicyConfig, PolicySpec};
+
+/// whether the prefix `net` contains the prefix `addr`
+fn contains((net, bits): (IpAddr, u8), (addr, addr_bits): (IpAddr, u8)) -> bool {
+    if addr_bits < bits {
+        return false;
+    }
+
+    match (net, addr) {
+        (IpAddr::V4(net), IpAddr::V4(addr)) => {
+            let mask = u32::MAX.checked_shl(32 - bits as u32).unwrap_or(0);
+            u32::from(net) & mask == u32::from(addr) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(addr)) => {
+            let mask = u128::MAX.checked_shl(128 - bits as u32).unwrap_or(0);
+            u128::from(net) & mask == u128::from(addr) & mask
+        }
+        _ => false,
+    }
+}
+
+/// whether a comma separated list of ports and ranges, or `*`, includes `port`
+fn includes_port(ports: &str, port: u16) -> bool {
+    ports == "*"
+        || ports
+            .split(',')
+            .filter_map(parse_port_range)
+            .any(|(start, end)| (start..=end).contains(&port))
+}
+
+/// the rule that decides a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// the acl at this index of the merged acl is the first that accepts the connection
+    Acl(usize),
+    /// no acl accepts the connection and the grant at this index allows it
+    Grant(usize),
+    /// no rule matches, connections are denied by default
+    Default,
+}
+
+/// identities of the nodes by their ip addresses, their tags or the user owning them, so ips are
+/// explained as the node they belong to
+pub type NodeIdentities = BTreeMap<String, Vec<String>>;
+
+/// the outcome of a connection between two identities
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub src: String,
+    pub dst: String,
+    pub port: u16,
+    pub allowed: bool,
+    pub decision: Decision,
+}
+
+/// evaluates connections against an acl without knowing the nodes, identities are matched by
+/// the aliases rules refer to them with
+#[derive(Clone, Copy)]
+pub(super) struct Evaluator<'a> {
+    pub(super) config: &'a PolicyConfig,
+}
+
+impl Evaluator<'_> {
+    /// prefix of an ip, cidr or host alias
+    fn address(&self, alias: &str) -> Option<(IpAddr, u8)> {
+        let hosts = self.config.hosts.as_ref();
+        let alias = hosts
+            .and_then(|hosts| hosts.get(alias))
+            .map_or(alias, String::as_str);
+
+        match alias.parse::<IpAddr>() {
+            Ok(addr) => Some((addr, if addr.is_ipv4() { 32 } else { 128 })),
+            Err(_) => parse_cidr(alias),
+        }
+    }
+
+    /// name of a user alias, written as `user@`, as an email or by its bare name
+    fn user<'b>(&self, alias: &'b str) -> Option<&'b str> {
+        if alias == "*" || alias.contains(':') {
+            return None;
+        }
+
+        match alias.contains('@') {
+            true => Some(alias.trim_end_matches('@')),
+            false => self.address(alias).is_none().then_some(alias),
+        }
+    }
+
+    /// identities an alias of a test stands for, a group stands for each of its members
+    fn identities(&self, alias: &str) -> Vec<String> {
+        match alias.starts_with("group:") {
+            true => self
+                .config
+                .groups
+                .as_ref()
+                .and_then(|groups| groups.get(alias))
+                .into_iter()
+                .flatten()
+                .map(|member| match member.contains('@') {
+                    true => member.clone(),
+                    false => format!("{member}@"),
+                })
+                .collect(),
+            false => vec![alias.to_string()],
+        }
+    }
+
+    /// whether an alias of a rule matches an identity
+    pub(super) fn covers(&self, rule: &str, identity: &str) -> bool {
+        match rule {
+            "*" => true,
+            "autogroup:member" => self.user(identity).is_some(),
+            "autogroup:tagged" => identity.starts_with("tag:"),
+            _ if rule == identity => true,
+            _ if rule.starts_with("group:") => self
+                .identities(rule)
+                .iter()
+                .any(|member| self.user(identity) == self.user(member)),
+            _ if self.user(rule).is_some() => self.user(rule) == self.user(identity),
+            _ => match (self.address(rule), self.address(identity)) {
+                (Some(net), Some(addr)) => contains(net, addr),
+                _ => false,
+            },
+        }
+    }
+
+    /// an identity along with the identities of the node behind it, when it is the ip or host of
+    /// a known node
+    fn with_node(&self, identity: &str, nodes: &NodeIdentities) -> Vec<String> {
+        let hosts = self.config.hosts.as_ref();
+        let address = hosts
+            .and_then(|hosts| hosts.get(identity))
+            .map_or(identity, String::as_str);
+
+        let node = nodes.get(address).into_iter().flatten().cloned();
+        std::iter::once(identity.to_string()).chain(node).collect()
+    }
+
+    /// whether a destination alias of a rule matches an identity reached from `src`
+    fn covers_destination(&self, rule: &str, identity: &str, src: &str) -> bool {
+        match rule {
+            "autogroup:self" => {
+                self.user(identity).is_some() && self.user(identity) == self.user(src)
+            }
+            _ => self.covers(rule, identity),
+        }
+    }
+
+    /// whether `src` may connect to `dst` on `port`
+    fn allows(&self, src: &str, dst: &str, port: u16) -> bool {
+        self.allowed(self.decide(src, dst, port))
+    }
+
+    /// whether a decision allows the connection
+    fn allowed(&self, decision: Decision) -> bool {
+        match decision {
+            Decision::Acl(_) | Decision::Grant(_) => true,
+            Decision::Default => false,
+        }
+    }
+
+    /// the rule that allows `src` to connect to `dst` on `port`. headscale allows the union of
+    /// the connections accepted by acls and grants, so the order of the rules doesn't matter and
+    /// the first one that accepts the connection is reported
+    fn decide(&self, src: &str, dst: &str, port: u16) -> Decision {
+        let acl = self.config.acls.iter().position(|acl| {
+            acl.action == Action::Accept
+                && acl.src.iter().any(|alias| self.covers(alias, src))
+                && acl.dst.iter().any(|alias| {
+                    alias.rsplit_once(':').is_some_and(|(alias, ports)| {
+                        self.covers_destination(alias, dst, src) && includes_port(ports, port)
+                    })
+                })
+        });
+        if let Some(i) = acl {
+            return Decision::Acl(i);
+        }
+
+        let grant = self.config.grants.iter().flatten().position(|grant| {
+            grant.src.iter().any(|alias| self.covers(alias, src))
+                && grant
+                    .dst
+                    .iter()
+                    .any(|alias| self.covers_destination(alias, dst, src))
+                && grant.ip.iter().any(|ip| match ip.split_once(':') {
+                    Some((_, ports)) => includes_port(ports, port),
+                    None => includes_port(ip, port),
+                })
+        });
+
+        grant.map_or(Decision::Default, Decision::Grant)
+    }
+}
+
+/// runs the tests of a policy against the merged acl, returning the expectations that break
+pub fn run_tests(spec: &PolicySpec, config: &PolicyConfig) -> Vec<FieldError> {
+    let evaluator = Evaluator { config };
+    let mut failures = Vec::new();
+
+    for (i, test) in spec.tests.iter().flatten().enumerate() {
+        let sources = evaluator.identities(&test.src);
+        let expectations = [("accept", &test.accept, true), ("deny", &test.deny, false)];
+
+        for (field, destinations, expected) in expectations {
+            for (j, dst) in destinations.iter().enumerate() {
+                let Some((alias, port)) = dst.rsplit_once(':') else {
+                    continue;
+                };
+                let Some(port) = parse_port(port) else {
+                    continue;
+                };
+
+                let targets = evaluator.identities(alias);
+                let broken = sources.iter().find(|src| {
+                    targets
+                        .iter()
+                        .any(|target| evaluator.allows(src, target, port) != expected)
+                });
+                if let Some(src) = broken {
+                    let outcome = if expected { "denied" } else { "accepted" };
+                    failures.push(FieldError {
+                        path: format!("spec.tests[{i}].{field}[{j}]"),
+                        message: format!("'{dst}' is {outcome} for '{src}'"),
+                    });
+                }
+            }
+        }
+    }
+
+    failures
+}
+
+/// explains whether `src` may reach `dst`, a destination with a single port such as `tag:web:443`,
+/// groups are explained for each of their members and the ips of `nodes` as the node they belong to
+pub fn explain(
+    config: &PolicyConfig,
+    src: &str,
+    dst: &str,
+    nodes: &NodeIdentities,
+) -> Result<Vec<Explanation>, String> {
+    let Some((alias, port)) = dst.rsplit_once(':') else {
+        return Err(format!(
+            "destination '{dst}' is missing a port, such as '{dst}:443'"
+        ));
+    };
+    let Some(port) = parse_port(port) else {
+        return Err(format!("invalid port '{port}'"));
+    };
+
+    let evaluator = Evaluator { config };
+    let targets = evaluator.identities(alias);
+    let explanations = evaluator
+        .identities(src)
+        .into_iter()
+        .flat_map(|src| {
+            targets.iter().map(move |dst| {
+                // a node is allowed the connections of any of its identities
+                let sources = evaluator.with_node(&src, nodes);
+                let destinations = evaluator.with_node(dst, nodes);
+                let decision = sources
+                    .iter()
+                    .flat_map(|src| destinations.iter().map(move |dst| (src, dst)))
+                    .map(|(src, dst)| evaluator.decide(src, dst, port))
+                    .find(|decision| evaluator.allowed(*decision))
+                    .unwrap_or(Decision::Default);
+                Explanation {
+                    src: src.clone(),
+                    dst: dst.clone(),
+                    port,
+                    allowed: evaluator.allowed(decision),
+                    decision,
+                }
+            })
+        })
+        .collect();
+
+    Ok(explanations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(value: serde_json::Value) -> PolicyConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn prefix(prefix: &str) -> (IpAddr, u8) {
+        match prefix.split_once('/') {
+            Some((addr, bits)) => (addr.parse().unwrap(), bits.parse().unwrap()),
+            None => {
+                let addr: IpAddr = prefix.parse().unwrap();
+                (addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        }
+    }
+
+    #[test]
+    fn contains_ipv4() {
+        assert!(contains(prefix("10.0.0.0/8"), prefix("10.1.2.3")));
+        assert!(contains(prefix("10.0.0.0/8"), prefix("10.1.0.0/16")));
+        assert!(contains(prefix("0.0.0.0/0"), prefix("192.168.1.1")));
+        assert!(!contains(prefix("10.0.0.0/8"), prefix("11.0.0.1")));
+        assert!(!contains(prefix("10.1.0.0/16"), prefix("10.0.0.0/8")));
+    }
+
+    #[test]
+    fn contains_ipv6() {
+        assert!(contains(
+            prefix("fd7a:115c:a1e0::/48"),
+            prefix("fd7a:115c:a1e0::1")
+        ));
+        assert!(contains(prefix("::/0"), prefix("2001:db8::1")));
+        assert!(!contains(
+            prefix("fd7a:115c:a1e0::/48"),
+            prefix("fd7a:115c:a1e1::1")
+        ));
+        assert!(!contains(prefix("::/0"), prefix("10.0.0.1")));
+    }
+
+    #[test]
+    fn includes_ports() {
+        assert!(includes_port("*", 22));
+        assert!(includes_port("22,80", 80));
+        assert!(includes_port("8000-8080", 8042));
+        assert!(!includes_port("8000-8080", 8081));
+        assert!(!includes_port("0", 0));
+    }
+
+    #[test]
+    fn evaluates_acls_and_grants() {
+        let config = config(serde_json::json!({
+            "groups": { "group:dev": ["alice@", "bob"] },
+            "hosts": { "db": "10.0.1.5", "internal": "10.0.0.0/16" },
+            "acls": [
+                { "action": "accept", "src": ["group:dev"], "dst": ["tag:web:80,443"] },
+                { "action": "accept", "src": ["tag:web"], "dst": ["internal:5432"] },
+            ],
+            "grants": [
+                { "src": ["autogroup:member"], "dst": ["autogroup:self"], "ip": ["*"] },
+            ],
+        }));
+        let evaluator = Evaluator { config: &config };
+
+        assert_eq!(evaluator.decide("bob@", "tag:web", 443), Decision::Acl(0));
+        assert_eq!(evaluator.decide("tag:web", "db", 5432), Decision::Acl(1));
+        assert_eq!(evaluator.decide("alice@", "alice@", 22), Decision::Grant(0));
+        assert_eq!(evaluator.decide("alice@", "tag:web", 22), Decision::Default);
+        assert_eq!(evaluator.decide("alice@", "bob@", 22), Decision::Default);
+        assert_eq!(
+            evaluator.decide("tag:web", "10.1.0.1", 5432),
+            Decision::Default
+        );
+    }
+
+    #[test]
+    fn explains_group_members_and_nodes() {
+        let config = config(serde_json::json!({
+            "groups": { "group:dev": ["alice@", "bob@"] },
+            "acls": [{ "action": "accept", "src": ["group:dev"], "dst": ["tag:web:443"] }],
+        }));
+        let nodes = NodeIdentities::from([("100.64.0.1".to_string(), vec!["tag:web".to_string()])]);
+
+        let explanations = explain(&config, "group:dev", "100.64.0.1:443", &nodes).unwrap();
+        assert_eq!(explanations.len(), 2);
+        assert!(explanations.iter().all(|explanation| explanation.allowed));
+
+        let explanations = explain(&config, "alice@", "100.64.0.1:443", &Default::default());
+        assert!(!explanations.unwrap()[0].allowed);
+        assert!(explain(&config, "alice@", "tag:web:0", &nodes).is_err());
+        assert!(explain(&config, "alice@", "tag:web", &nodes).is_err());
+    }
+
+    #[test]
+    fn runs_tests() {
+        let spec: PolicySpec = serde_json::from_value(serde_json::json!({
+            "headscaleRef": { "name": "example" },
+            "acls": [{ "action": "accept", "src": ["alice@"], "dst": ["tag:web:443"] }],
+            "tests": [
+                { "src": "alice@", "accept": ["tag:web:443"], "deny": ["tag:web:22"] },
+                { "src": "bob@", "accept": ["tag:web:443"] },
+            ],
+        }))
+        .unwrap();
+        let config = PolicyConfig {
+            acls: spec.acls.clone(),
+            ..Default::default()
+        };
+
+        let failures = run_tests(&spec, &config);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].path, "spec.tests[1].accept[0]");
+    }
+}
@@ -0,0 +1,164 @@
+use crate::acl::validate::FieldError;
+use crate::crds::policy::{PolicyConfig, PolicySpec};
+
+/// skips over a string literal starting at `i`, returning the index after the closing quote
+fn skip_string(bytes: &[u8], mut i: usize) -> usize {
+    i += 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    i
+}
+
+/// strips comments from hujson, keeping newlines so positions in errors still match the input
+fn strip_comments(input: &str) -> Result<Vec<u8>, String> {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (b'"', _) => {
+                let end = skip_string(bytes, i).min(bytes.len());
+                output.extend_from_slice(&bytes[i..end]);
+                i = end;
+            }
+            (b'/', Some(b'/')) => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            (b'/', Some(b'*')) => {
+                let Some(end) = input[i + 2..].find("*/") else {
+                    return Err("unterminated block comment".to_string());
+                };
+                let end = i + 2 + end + 2;
+                output.extend(bytes[i..end].iter().filter(|&&b| b == b'\n'));
+                i = end;
+            }
+            (byte, _) => {
+                output.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// drops commas that are only followed by whitespace and a closing bracket
+fn strip_trailing_commas(bytes: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                let end = skip_string(bytes, i).min(bytes.len());
+                output.extend_from_slice(&bytes[i..end]);
+                i = end;
+            }
+            b',' => {
+                let next = bytes[i + 1..].iter().find(|b| !b.is_ascii_whitespace());
+                if !matches!(next, Some(b'}' | b']')) {
+                    output.push(b',');
+                }
+                i += 1;
+            }
+            byte => {
+                output.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    output
+}
+
+/// turns hujson, json with comments and trailing commas, into plain json
+fn standardize(input: &str) -> Result<String, String> {
+    let output = strip_trailing_commas(&strip_comments(input)?);
+    String::from_utf8(output).map_err(|err| err.to_string())
+}
+
+/// the spec a raw policy stands for, `content` is the hujson it refers to, selectors are kept
+pub fn resolve(spec: &PolicySpec, content: &str) -> Result<PolicySpec, FieldError> {
+    let error = |message: String| FieldError {
+        path: "spec.raw".to_string(),
+        message,
+    };
+
+    let inline = spec.groups.is_some()
+        || spec.hosts.is_some()
+        || spec.tag_owners.is_some()
+        || spec.postures.is_some()
+        || !spec.acls.is_empty()
+        || spec.auto_approvers.is_some()
+        || spec.ssh.is_some()
+        || spec.grants.is_some()
+        || spec.tests.is_some();
+    if inline {
+        return Err(error("can't be combined with inline policy fields".into()));
+    }
+
+    let json = standardize(content).map_err(error)?;
+    let config: PolicyConfig = serde_json::from_str(&json).map_err(|err| error(err.to_string()))?;
+
+    Ok(PolicySpec {
+        headscale_ref: spec.headscale_ref.clone(),
+        raw: None,
+        groups: config.groups,
+        group_selectors: spec.group_selectors.clone(),
+        hosts: config.hosts,
+        host_selectors: spec.host_selectors.clone(),
+        tag_owners: config.tag_owners,
+        postures: config.postures,
+        acls: config.acls,
+        auto_approvers: config.auto_approvers,
+        ssh: config.ssh,
+        grants: config.grants,
+        tests: config.tests,
+        extra: config.extra,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_comments() {
+        let input = "{\n  // line comment\n  \"a\": 1, /* block\n comment */ \"b\": 2\n}";
+        let json = standardize(input).unwrap();
+
+        assert_eq!(json.lines().count(), input.lines().count());
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value, serde_json::json!({ "a": 1, "b": 2 }));
+    }
+
+    #[test]
+    fn keeps_comment_markers_in_strings() {
+        let json = standardize(r#"{"url": "http://example.com/*", "quote": "a \" // b"}"#).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(value["url"], "http://example.com/*");
+        assert_eq!(value["quote"], "a \" // b");
+    }
+
+    #[test]
+    fn strips_trailing_commas() {
+        let json = standardize("{\"a\": [1, 2,\n ], \"b\": \",]\",\n}").unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(value, serde_json::json!({ "a": [1, 2], "b": ",]" }));
+    }
+
+    #[test]
+    fn rejects_unterminated_block_comment() {
+        assert!(standardize("{ /* never closed }").is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeSet;
+
+use crate::acl::eval::Evaluator;
+use crate::acl::render::Aliases;
+use crate::acl::validate::{FieldError, keys, parse_port_range};
+use crate::crds::policy::{Acl, Action, PolicyConfig, PolicySpec};
+
+/// whether the ports of `outer` include every port of `inner`
+fn ports_cover(outer: &str, inner: &str) -> bool {
+    if outer == "*" {
+        return true;
+    }
+    if inner == "*" {
+        return false;
+    }
+
+    let outer: Vec<_> = outer.split(',').filter_map(parse_port_range).collect();
+    inner.split(',').all(|range| {
+        parse_port_range(range).is_some_and(|(start, end)| {
+            outer
+                .iter()
+                .any(|&(outer_start, outer_end)| outer_start <= start && end <= outer_end)
+        })
+    })
+}
+
+/// whether every connection `inner` accepts is already accepted by `outer`
+fn acl_covers(evaluator: &Evaluator, outer: &Acl, inner: &Acl) -> bool {
+    if outer.action != Action::Accept || inner.action != Action::Accept {
+        return false;
+    }
+    if outer.src_posture.is_some() && outer.src_posture != inner.src_posture {
+        return false;
+    }
+
+    let sources = inner
+        .src
+        .iter()
+        .all(|src| outer.src.iter().any(|alias| evaluator.covers(alias, src)));
+    let destinations = inner.dst.iter().all(|dst| {
+        let Some((alias, ports)) = dst.rsplit_once(':') else {
+            return false;
+        };
+        outer.dst.iter().any(|outer| {
+            outer
+                .rsplit_once(':')
+                .is_some_and(|(outer_alias, outer_ports)| {
+                    evaluator.covers(outer_alias, alias) && ports_cover(outer_ports, ports)
+                })
+        })
+    });
+
+    sources && destinations
+}
+
+/// aliases the rules and definitions of the acl refer to
+fn references(config: &PolicyConfig) -> BTreeSet<String> {
+    let mut config = config.clone();
+    let mut references = BTreeSet::new();
+    Aliases::from(&mut config).all(&mut |_, alias| {
+        references.insert(alias.clone());
+    });
+
+    references
+}
+
+/// an earlier acl of the merged acl, `policy` is `namespace/name` of the policy it comes from
+#[derive(Clone, Copy)]
+pub struct Earlier<'a> {
+    pub policy: &'a str,
+    pub index: usize,
+    pub acl: &'a Acl,
+}
+
+/// rules of a policy that are redundant or allow too much, and definitions nothing refers to
+///
+/// `earlier` are the acls that precede those of the policy in the merged acl. headscale allows
+/// the union of the connections the acls accept, so an acl that an earlier one covers adds nothing
+pub fn lint(
+    policy: &str,
+    spec: &PolicySpec,
+    earlier: &[Earlier],
+    config: &PolicyConfig,
+) -> Vec<FieldError> {
+    let evaluator = Evaluator { config };
+    let mut warnings = Vec::new();
+    let mut warn = |path: String, message: String| warnings.push(FieldError { path, message });
+
+    for (i, acl) in spec.acls.iter().enumerate() {
+        let path = format!("spec.acls[{i}]");
+        let own = spec.acls[..i]
+            .iter()
+            .enumerate()
+            .map(|(index, acl)| Earlier { policy, index, acl });
+        let covered_by = earlier
+            .iter()
+            .copied()
+            .chain(own)
+            .find(|earlier| acl_covers(&evaluator, earlier.acl, acl));
+
+        if let Some(earlier) = covered_by {
+            let mut by = format!("spec.acls[{}]", earlier.index);
+            if earlier.policy != policy {
+                by = format!("{by} of policy {}", earlier.policy);
+            }
+            match earlier.acl == acl {
+                true => warn(path.clone(), format!("duplicate of {by}")),
+                false => warn(
+                    path.clone(),
+                    format!("redundant, {by} already accepts every connection it does"),
+                ),
+            }
+        }
+
+        let wildcard = acl.src.iter().any(|src| src == "*")
+            && acl.dst.iter().any(|dst| dst == "*:*")
+            && acl.src_posture.is_none();
+        if acl.action == Action::Accept && wildcard {
+            warn(
+                path,
+                "allows every node to reach every port of every node".into(),
+            );
+        }
+    }
+
+    for (i, grant) in spec.grants.iter().flatten().enumerate() {
+        let wildcard = grant.src.iter().any(|src| src == "*")
+            && grant.dst.iter().any(|dst| dst == "*")
+            && grant.ip.iter().any(|ip| ip == "*");
+        if wildcard {
+            warn(
+                format!("spec.grants[{i}]"),
+                "allows every node to reach every port of every node".into(),
+            );
+        }
+    }
+
+    let references = references(config);
+    let definitions = [
+        ("groups", "group", keys(spec.groups.as_ref())),
+        ("hosts", "host", keys(spec.hosts.as_ref())),
+        ("tagOwners", "tag", keys(spec.tag_owners.as_ref())),
+    ];
+    for (field, kind, names) in definitions {
+        for name in &names {
+            if !references.contains(name) {
+                warn(
+                    format!("spec.{field}[{name}]"),
+                    format!("{kind} '{name}' is not used by any rule"),
+                );
+            }
+        }
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// warnings of a policy on its own, as if it was the only policy of its instance
+    fn warnings(spec: serde_json::Value) -> Vec<FieldError> {
+        let mut spec = spec;
+        spec["headscaleRef"] = serde_json::json!({ "name": "example" });
+        let spec: PolicySpec = serde_json::from_value(spec).unwrap();
+        let config = PolicyConfig {
+            groups: spec.groups.clone(),
+            hosts: spec.hosts.clone(),
+            tag_owners: spec.tag_owners.clone(),
+            acls: spec.acls.clone(),
+            grants: spec.grants.clone(),
+            ..Default::default()
+        };
+
+        lint("default/example", &spec, &[], &config)
+    }
+
+    #[test]
+    fn covers_port_ranges() {
+        assert!(ports_cover("*", "22"));
+        assert!(ports_cover("80,443", "443"));
+        assert!(ports_cover("8000-8080", "8000,8042-8080"));
+        assert!(!ports_cover("8000-8080", "7999-8000"));
+        assert!(!ports_cover("80", "*"));
+    }
+
+    #[test]
+    fn reports_duplicate_and_redundant_acls() {
+        let warnings = warnings(serde_json::json!({
+            "acls": [
+                { "action": "accept", "src": ["alice@"], "dst": ["tag:web:80,443"] },
+                { "action": "accept", "src": ["alice@"], "dst": ["tag:web:80,443"] },
+                { "action": "accept", "src": ["alice@"], "dst": ["tag:web:443"] },
+                { "action": "accept", "src": ["alice@"], "dst": ["tag:web:22"] },
+            ],
+        }));
+
+        let paths: Vec<_> = warnings
+            .iter()
+            .map(|warning| warning.path.as_str())
+            .collect();
+        assert_eq!(paths, ["spec.acls[1]", "spec.acls[2]"]);
+        assert!(warnings[0].message.starts_with("duplicate of spec.acls[0]"));
+        assert!(warnings[1].message.starts_with("redundant"));
+    }
+
+    #[test]
+    fn reports_wildcard_rules() {
+        let warnings = warnings(serde_json::json!({
+            "acls": [{ "action": "accept", "src": ["*"], "dst": ["*:*"] }],
+            "grants": [{ "src": ["*"], "dst": ["*"], "ip": ["*"] }],
+        }));
+
+        let paths: Vec<_> = warnings
+            .iter()
+            .map(|warning| warning.path.as_str())
+            .collect();
+        assert_eq!(paths, ["spec.acls[0]", "spec.grants[0]"]);
+    }
+
+    #[test]
+    fn reports_unused_definitions() {
+        let warnings = warnings(serde_json::json!({
+            "groups": { "group:dev": ["alice@"], "group:ops": ["bob@"] },
+            "acls": [{ "action": "accept", "src": ["group:dev"], "dst": ["tag:web:443"] }],
+        }));
+
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].path.contains("group:ops"));
+    }
+}
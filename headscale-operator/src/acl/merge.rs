@@ -0,0 +1,401 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use kube::ResourceExt as _;
+
+use crate::acl::eval::{Decision, run_tests};
+use crate::acl::lint::{Earlier, lint};
+use crate::acl::render::deprecations;
+use crate::acl::validate::{FieldError, Scope, Target, keys, validate};
+use crate::crds::policy::{AutoApprovers, Groups, Policy, PolicyConfig, PolicySpec};
+use crate::helper::ResourceExt as _;
+
+/// a definition that was already made by another policy with a different value, the first
+/// definition in merge order wins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// `namespace/name` of the policy whose definition was ignored
+    pub policy: String,
+    pub kind: &'static str,
+    pub name: String,
+    /// the policy or group resource whose definition is used, such as `policy namespace/name`
+    pub defined_by: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} '{}' is already defined differently by {}",
+            self.kind, self.name, self.defined_by
+        )
+    }
+}
+
+/// a policy that was left out of the merge because it doesn't validate
+#[derive(Debug, Clone)]
+pub struct InvalidPolicy {
+    /// `namespace/name` of the policy
+    pub policy: String,
+    pub errors: Vec<FieldError>,
+}
+
+/// tests of a policy that don't hold for the merged acl
+#[derive(Debug, Clone)]
+pub struct FailedTests {
+    /// `namespace/name` of the policy
+    pub policy: String,
+    pub failures: Vec<FieldError>,
+}
+
+/// problems with a policy that don't keep it out of the acl
+#[derive(Debug, Clone)]
+pub struct PolicyWarnings {
+    /// `namespace/name` of the policy
+    pub policy: String,
+    pub warnings: Vec<FieldError>,
+}
+
+#[derive(Debug, Default)]
+pub struct MergedPolicy {
+    pub config: PolicyConfig,
+    pub conflicts: Vec<Conflict>,
+    pub invalid: Vec<InvalidPolicy>,
+    /// the acl must not be applied while any test fails
+    pub failed_tests: Vec<FailedTests>,
+    pub warnings: Vec<PolicyWarnings>,
+}
+
+impl MergedPolicy {
+    /// validation errors of the given policy, empty when it was merged
+    pub fn errors_of(&self, policy: &Policy) -> &[FieldError] {
+        let key = policy_key(policy);
+        self.invalid
+            .iter()
+            .find(|invalid| invalid.policy == key)
+            .map(|invalid| invalid.errors.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// failing tests of the given policy
+    pub fn failures_of(&self, policy: &Policy) -> &[FieldError] {
+        let key = policy_key(policy);
+        self.failed_tests
+            .iter()
+            .find(|failed| failed.policy == key)
+            .map(|failed| failed.failures.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// warnings of the given policy
+    pub fn warnings_of(&self, policy: &Policy) -> &[FieldError] {
+        let key = policy_key(policy);
+        self.warnings
+            .iter()
+            .find(|warnings| warnings.policy == key)
+            .map(|warnings| warnings.warnings.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// `namespace/name` of the policy a rule of the merged acl comes from, and the path of the rule
+    /// in that policy, `policies` are the policies the acl was built from
+    pub fn origin(&self, policies: &[Policy], decision: Decision) -> Option<(String, String)> {
+        let (index, field, count): (_, _, fn(&PolicySpec) -> usize) = match decision {
+            Decision::Acl(i) => (i, "acls", |spec| spec.acls.len()),
+            Decision::Grant(i) => (i, "grants", |spec| spec.grants.as_ref().map_or(0, Vec::len)),
+            Decision::Default => return None,
+        };
+
+        let mut merged: Vec<_> = policies
+            .iter()
+            .map(|policy| (policy_key(policy), policy))
+            .filter(|(key, _)| !self.invalid.iter().any(|invalid| &invalid.policy == key))
+            .collect();
+        merged.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut offset = 0;
+        for (key, policy) in merged {
+            let count = count(&policy.spec);
+            if index < offset + count {
+                return Some((key, format!("spec.{field}[{}]", index - offset)));
+            }
+            offset += count;
+        }
+
+        None
+    }
+
+    /// records a policy that couldn't be merged at all
+    pub fn reject(&mut self, policy: &Policy, errors: Vec<FieldError>) {
+        let policy = policy_key(policy);
+        self.invalid.push(InvalidPolicy { policy, errors });
+    }
+
+    /// conflicts caused by the given policy
+    pub fn conflicts_of<'a>(&'a self, policy: &Policy) -> impl Iterator<Item = &'a Conflict> {
+        let key = policy_key(policy);
+        self.conflicts
+            .iter()
+            .filter(move |conflict| conflict.policy == key)
+    }
+}
+
+fn policy_key(policy: &Policy) -> String {
+    format!("{}/{}", policy.namespace_any(), policy.name_any())
+}
+
+/// merges named definitions, keeping track of which policy defined what
+struct Definitions<V> {
+    kind: &'static str,
+    entries: BTreeMap<String, (V, String)>,
+}
+
+impl<V: Clone + PartialEq> Definitions<V> {
+    fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// adds definitions that precede those of policies, the first definition of a name wins
+    fn seed(&mut self, source: &BTreeMap<String, V>, defined_by: String) {
+        for (name, value) in source {
+            self.entries
+                .entry(name.clone())
+                .or_insert_with(|| (value.clone(), defined_by.clone()));
+        }
+    }
+
+    fn extend(
+        &mut self,
+        source: Option<&BTreeMap<String, V>>,
+        policy: &str,
+        conflicts: &mut Vec<Conflict>,
+    ) {
+        for (name, value) in source.into_iter().flatten() {
+            match self.entries.get(name) {
+                Some((existing, _)) if existing == value => {}
+                Some((_, defined_by)) => conflicts.push(Conflict {
+                    policy: policy.to_string(),
+                    kind: self.kind,
+                    name: name.clone(),
+                    defined_by: defined_by.clone(),
+                }),
+                None => {
+                    let defined_by = format!("policy {policy}");
+                    self.entries
+                        .insert(name.clone(), (value.clone(), defined_by));
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> Option<BTreeMap<String, V>> {
+        (!self.entries.is_empty()).then(|| {
+            self.entries
+                .into_iter()
+                .map(|(name, (value, _))| (name, value))
+                .collect()
+        })
+    }
+}
+
+fn merge_auto_approvers(target: &mut AutoApprovers, source: Option<&AutoApprovers>) {
+    let Some(source) = source else {
+        return;
+    };
+
+    for (route, approvers) in &source.routes {
+        let existing = target.routes.entry(route.clone()).or_default();
+        for approver in approvers {
+            if !existing.contains(approver) {
+                existing.push(approver.clone());
+            }
+        }
+    }
+
+    for approver in &source.exit_node {
+        if !target.exit_node.contains(approver) {
+            target.exit_node.push(approver.clone());
+        }
+    }
+}
+
+/// merges policies into a single acl, policies are merged ordered by namespace and name so the
+/// result doesn't depend on the order they were listed in, groups of group resources go first
+fn merge<'a>(policies: impl IntoIterator<Item = &'a Policy>, target: &Target) -> MergedPolicy {
+    let mut policies: Vec<_> = policies
+        .into_iter()
+        .map(|policy| (policy_key(policy), policy))
+        .collect();
+    policies.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut conflicts = Vec::new();
+    let mut groups = Definitions::new("group");
+    for (key, definitions) in &target.groups {
+        groups.seed(definitions, format!("Group {key}"));
+    }
+    let mut hosts = Definitions::new("host");
+    let mut tag_owners = Definitions::new("tagOwner");
+    let mut postures = Definitions::new("posture");
+    let mut extra = Definitions::new("option");
+    let mut acls = Vec::new();
+    let mut auto_approvers = AutoApprovers::default();
+    let mut ssh = Vec::new();
+    let mut grants = Vec::new();
+    let mut tests = Vec::new();
+
+    for (key, policy) in &policies {
+        let spec = &policy.spec;
+        groups.extend(spec.groups.as_ref(), key, &mut conflicts);
+        hosts.extend(spec.hosts.as_ref(), key, &mut conflicts);
+        tag_owners.extend(spec.tag_owners.as_ref(), key, &mut conflicts);
+        postures.extend(spec.postures.as_ref(), key, &mut conflicts);
+        extra.extend(Some(&spec.extra), key, &mut conflicts);
+        acls.extend(spec.acls.iter().cloned());
+        merge_auto_approvers(&mut auto_approvers, spec.auto_approvers.as_ref());
+        ssh.extend(spec.ssh.iter().flatten().cloned());
+        grants.extend(spec.grants.iter().flatten().cloned());
+        tests.extend(spec.tests.iter().flatten().cloned());
+    }
+
+    MergedPolicy {
+        config: PolicyConfig {
+            groups: groups.finish(),
+            hosts: hosts.finish(),
+            tag_owners: tag_owners.finish(),
+            postures: postures.finish(),
+            acls,
+            auto_approvers: (auto_approvers != AutoApprovers::default()).then_some(auto_approvers),
+            ssh: (!ssh.is_empty()).then_some(ssh),
+            grants: (!grants.is_empty()).then_some(grants),
+            tests: (!tests.is_empty()).then_some(tests),
+            extra: extra.finish().unwrap_or_default(),
+        },
+        conflicts,
+        invalid: Vec::new(),
+        failed_tests: Vec::new(),
+        warnings: Vec::new(),
+    }
+}
+
+/// deprecations and lint warnings of the given policies, which are linted in merge order
+fn warnings<'a>(
+    policies: impl IntoIterator<Item = &'a Policy>,
+    config: &PolicyConfig,
+) -> Vec<PolicyWarnings> {
+    let mut policies: Vec<_> = policies
+        .into_iter()
+        .map(|policy| (policy_key(policy), policy))
+        .collect();
+    policies.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let hosts = keys(config.hosts.as_ref());
+    let mut earlier = Vec::new();
+    let mut result = Vec::new();
+    for (key, policy) in &policies {
+        let mut warnings = deprecations(&policy.spec, &hosts);
+        warnings.extend(lint(key, &policy.spec, &earlier, config));
+        if !warnings.is_empty() {
+            result.push(PolicyWarnings {
+                policy: key.clone(),
+                warnings,
+            });
+        }
+
+        earlier.extend(
+            policy
+                .spec
+                .acls
+                .iter()
+                .enumerate()
+                .map(|(index, acl)| Earlier {
+                    policy: key,
+                    index,
+                    acl,
+                }),
+        );
+    }
+
+    result
+}
+
+/// merges the policies that validate for the target headscale instance into a single acl
+///
+/// leaving out an invalid policy can remove definitions others depend on, so validation is
+/// repeated until every remaining policy validates
+pub fn build(policies: &[Policy], target: &Target) -> MergedPolicy {
+    let mut valid: Vec<_> = policies.iter().collect();
+    let mut invalid = Vec::new();
+
+    loop {
+        let merged = merge(valid.iter().copied(), target);
+        let scope = Scope::new(&merged.config, target);
+
+        let before = valid.len();
+        valid.retain(|policy| {
+            let errors = validate(&policy.spec, &scope);
+            if errors.is_empty() {
+                return true;
+            }
+
+            invalid.push(InvalidPolicy {
+                policy: policy_key(policy),
+                errors,
+            });
+            false
+        });
+
+        if valid.len() == before {
+            let failed_tests = valid
+                .iter()
+                .map(|policy| FailedTests {
+                    policy: policy_key(policy),
+                    failures: run_tests(&policy.spec, &merged.config),
+                })
+                .filter(|failed| !failed.failures.is_empty())
+                .collect();
+            let warnings = warnings(valid.iter().copied(), &merged.config);
+
+            return MergedPolicy {
+                invalid,
+                failed_tests,
+                warnings,
+                ..merged
+            };
+        }
+    }
+}
+
+/// validates a policy against the definitions of the other policies and group resources of its
+/// headscale instance, users and the headscale version aren't checked as they may not be known yet
+///
+/// returns the errors and the warnings of the policy
+pub fn check(
+    policy: &Policy,
+    others: &[Policy],
+    groups: Vec<(String, Groups)>,
+) -> (Vec<FieldError>, Vec<FieldError>) {
+    let key = policy_key(policy);
+    let policies: Vec<_> = others
+        .iter()
+        .filter(|other| policy_key(other) != key)
+        .chain([policy])
+        .collect();
+    let target = Target {
+        groups,
+        ..Target::default()
+    };
+    let merged = merge(policies.iter().copied(), &target);
+    let scope = Scope::new(&merged.config, &target);
+
+    let errors = validate(&policy.spec, &scope);
+    let warnings = warnings(policies.iter().copied(), &merged.config)
+        .into_iter()
+        .find(|warnings| warnings.policy == key)
+        .map(|warnings| warnings.warnings)
+        .unwrap_or_default();
+
+    (errors, warnings)
+}
@@ -0,0 +1,14 @@
+//! rendering of policy resources into a single headscale acl
+
+mod eval;
+mod hujson;
+mod lint;
+mod merge;
+mod render;
+mod validate;
+
+pub use eval::{Decision, NodeIdentities, explain};
+pub use hujson::resolve;
+pub use merge::{MergedPolicy, build, check};
+pub use render::{migrate, render};
+pub use validate::{FieldError, POLICY_CHECK_MIN_VERSION, Target, at_least};
@@ -0,0 +1,222 @@
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+
+use crate::acl::validate::{FieldError, POLICY_V2_MIN_VERSION, at_least, keys, parse_cidr};
+use crate::crds::policy::{
+    Acl, AclTest, AutoApprovers, Grant, Groups, PolicyConfig, PolicySpec, SshRule, TagOwners,
+};
+
+/// the fields of a policy that refer to users, groups, tags, hosts or ips
+pub(super) struct Aliases<'a> {
+    groups: Option<&'a mut Groups>,
+    tag_owners: Option<&'a mut TagOwners>,
+    auto_approvers: Option<&'a mut AutoApprovers>,
+    acls: &'a mut Vec<Acl>,
+    ssh: Option<&'a mut Vec<SshRule>>,
+    grants: Option<&'a mut Vec<Grant>>,
+    tests: Option<&'a mut Vec<AclTest>>,
+}
+
+impl<'a> From<&'a mut PolicySpec> for Aliases<'a> {
+    fn from(spec: &'a mut PolicySpec) -> Self {
+        Self {
+            groups: spec.groups.as_mut(),
+            tag_owners: spec.tag_owners.as_mut(),
+            auto_approvers: spec.auto_approvers.as_mut(),
+            acls: &mut spec.acls,
+            ssh: spec.ssh.as_mut(),
+            grants: spec.grants.as_mut(),
+            tests: spec.tests.as_mut(),
+        }
+    }
+}
+
+impl<'a> From<&'a mut PolicyConfig> for Aliases<'a> {
+    fn from(config: &'a mut PolicyConfig) -> Self {
+        Self {
+            groups: config.groups.as_mut(),
+            tag_owners: config.tag_owners.as_mut(),
+            auto_approvers: config.auto_approvers.as_mut(),
+            acls: &mut config.acls,
+            ssh: config.ssh.as_mut(),
+            grants: config.grants.as_mut(),
+            tests: config.tests.as_mut(),
+        }
+    }
+}
+
+/// calls `f` with the alias of a destination such as `tag:web:443`, keeping the ports
+fn destination(path: &str, dst: &mut String, f: &mut impl FnMut(&str, &mut String)) {
+    let Some((alias, ports)) = dst.rsplit_once(':') else {
+        return;
+    };
+
+    let (mut alias, ports) = (alias.to_string(), ports.to_string());
+    f(path, &mut alias);
+    *dst = format!("{alias}:{ports}");
+}
+
+impl Aliases<'_> {
+    /// calls `f` with the path and value of every user, group or tag that owns or approves
+    /// something
+    fn owners(&mut self, f: &mut impl FnMut(&str, &mut String)) {
+        for (name, members) in self.groups.iter_mut().flat_map(|groups| groups.iter_mut()) {
+            for (i, member) in members.iter_mut().enumerate() {
+                f(&format!("spec.groups[{name}][{i}]"), member);
+            }
+        }
+
+        for (name, owners) in self.tag_owners.iter_mut().flat_map(|tags| tags.iter_mut()) {
+            for (i, owner) in owners.iter_mut().enumerate() {
+                f(&format!("spec.tagOwners[{name}][{i}]"), owner);
+            }
+        }
+
+        if let Some(auto_approvers) = self.auto_approvers.as_deref_mut() {
+            for (route, approvers) in auto_approvers.routes.iter_mut() {
+                for (i, approver) in approvers.iter_mut().enumerate() {
+                    f(
+                        &format!("spec.autoApprovers.routes[{route}][{i}]"),
+                        approver,
+                    );
+                }
+            }
+            for (i, approver) in auto_approvers.exit_node.iter_mut().enumerate() {
+                f(&format!("spec.autoApprovers.exitNode[{i}]"), approver);
+            }
+        }
+    }
+
+    /// calls `f` with the path and value of every alias, destinations are passed without ports
+    pub(super) fn all(&mut self, f: &mut impl FnMut(&str, &mut String)) {
+        self.owners(f);
+
+        for (i, acl) in self.acls.iter_mut().enumerate() {
+            for (j, src) in acl.src.iter_mut().enumerate() {
+                f(&format!("spec.acls[{i}].src[{j}]"), src);
+            }
+            for (j, dst) in acl.dst.iter_mut().enumerate() {
+                destination(&format!("spec.acls[{i}].dst[{j}]"), dst, f);
+            }
+        }
+
+        for (i, rule) in self
+            .ssh
+            .iter_mut()
+            .flat_map(|ssh| ssh.iter_mut())
+            .enumerate()
+        {
+            for (j, src) in rule.src.iter_mut().enumerate() {
+                f(&format!("spec.ssh[{i}].src[{j}]"), src);
+            }
+            for (j, dst) in rule.dst.iter_mut().enumerate() {
+                f(&format!("spec.ssh[{i}].dst[{j}]"), dst);
+            }
+        }
+
+        for (i, grant) in self
+            .grants
+            .iter_mut()
+            .flat_map(|grants| grants.iter_mut())
+            .enumerate()
+        {
+            for (j, src) in grant.src.iter_mut().enumerate() {
+                f(&format!("spec.grants[{i}].src[{j}]"), src);
+            }
+            for (j, dst) in grant.dst.iter_mut().enumerate() {
+                f(&format!("spec.grants[{i}].dst[{j}]"), dst);
+            }
+        }
+
+        for (i, test) in self
+            .tests
+            .iter_mut()
+            .flat_map(|tests| tests.iter_mut())
+            .enumerate()
+        {
+            f(&format!("spec.tests[{i}].src"), &mut test.src);
+            for (j, dst) in test.accept.iter_mut().enumerate() {
+                destination(&format!("spec.tests[{i}].accept[{j}]"), dst, f);
+            }
+            for (j, dst) in test.deny.iter_mut().enumerate() {
+                destination(&format!("spec.tests[{i}].deny[{j}]"), dst, f);
+            }
+        }
+    }
+}
+
+/// whether an alias is a user written without the trailing `@`, rather than a host, ip or cidr
+fn is_bare_user(alias: &str, hosts: &BTreeSet<String>) -> bool {
+    alias != "*"
+        && !alias.contains(['@', ':'])
+        && !hosts.contains(alias)
+        && alias.parse::<IpAddr>().is_err()
+        && parse_cidr(alias).is_none()
+}
+
+/// whether an alias is a user written as `user@`, as opposed to an email
+fn is_qualified_user(alias: &str) -> bool {
+    alias.len() > 1 && alias.find('@') == Some(alias.len() - 1)
+}
+
+/// users that are written without the trailing `@` headscale 0.26 and newer require, `hosts` are
+/// the names of the hosts the spec can refer to, which are written bare as well
+pub fn deprecations(spec: &PolicySpec, hosts: &BTreeSet<String>) -> Vec<FieldError> {
+    let mut spec = spec.clone();
+    let mut warnings = Vec::new();
+
+    let mut hosts = hosts.clone();
+    hosts.extend(keys(spec.hosts.as_ref()));
+
+    Aliases::from(&mut spec).all(&mut |path, alias| {
+        if is_bare_user(alias, &hosts) {
+            warnings.push(FieldError {
+                path: path.to_string(),
+                message: format!("user '{alias}' should be written as '{alias}@'"),
+            });
+        }
+    });
+
+    warnings
+}
+
+/// the spec with its users written as `user@`, the form of the current policy format
+pub fn migrate(spec: &PolicySpec, hosts: &BTreeSet<String>) -> PolicySpec {
+    let mut spec = spec.clone();
+
+    let mut hosts = hosts.clone();
+    hosts.extend(keys(spec.hosts.as_ref()));
+
+    Aliases::from(&mut spec).all(&mut |_, alias| {
+        if is_bare_user(alias, &hosts) {
+            alias.push('@');
+        }
+    });
+
+    spec
+}
+
+/// translates the merged acl into the policy format of the given headscale version, users are
+/// written as `user@` from 0.26 on and by their bare name before that
+pub fn render(config: &PolicyConfig, version: Option<&str>) -> PolicyConfig {
+    let mut config = config.clone();
+    let hosts = keys(config.hosts.as_ref());
+    let mut aliases = Aliases::from(&mut config);
+
+    match version {
+        Some(version) if !at_least(version, POLICY_V2_MIN_VERSION) => {
+            aliases.all(&mut |_, alias| {
+                if is_qualified_user(alias) {
+                    alias.pop();
+                }
+            })
+        }
+        _ => aliases.all(&mut |_, alias| {
+            if is_bare_user(alias, &hosts) {
+                alias.push('@');
+            }
+        }),
+    }
+
+    config
+}
@@ -0,0 +1,655 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::IpAddr;
+
+use version_compare::Version;
+
+use crate::crds::policy::{Action, Groups, PolicyConfig, PolicySpec, SshAction};
+
+/// a problem with a policy, `path` points at the offending field using the resource's field names
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// first headscale version that understands grants
+pub const GRANTS_MIN_VERSION: &str = "0.28.0";
+
+/// first headscale version with the second policy format, which introduced autogroups for users
+/// and tags and requires users to be written as `user@`
+pub const POLICY_V2_MIN_VERSION: &str = "0.26.0";
+
+/// first headscale version that can check a policy file with `headscale policy check`
+pub const POLICY_CHECK_MIN_VERSION: &str = "0.26.0";
+
+/// whether `version` is `min` or newer, unparsable versions are considered older
+pub fn at_least(version: &str, min: &str) -> bool {
+    match (Version::from(version), Version::from(min)) {
+        (Some(version), Some(min)) => version >= min,
+        _ => false,
+    }
+}
+
+/// the headscale instance policies are validated for
+#[derive(Debug, Default, Clone)]
+pub struct Target {
+    /// names and emails of the users of the instance, users aren't checked when unknown
+    pub users: Option<BTreeSet<String>>,
+    /// only looked up when a policy uses a feature that depends on it
+    pub version: Option<String>,
+    /// groups defined by group resources, keyed by `namespace/name` of the resource
+    pub groups: Vec<(String, Groups)>,
+}
+
+/// names a policy can refer to, gathered from the merged acl and the target instance
+#[derive(Debug, Default, Clone)]
+pub struct Scope {
+    pub users: Option<BTreeSet<String>>,
+    pub groups: BTreeSet<String>,
+    pub hosts: BTreeSet<String>,
+    pub tags: BTreeSet<String>,
+    pub postures: BTreeSet<String>,
+    pub version: Option<String>,
+}
+
+pub(super) fn keys<V>(map: Option<&BTreeMap<String, V>>) -> BTreeSet<String> {
+    map.into_iter()
+        .flat_map(|map| map.keys().cloned())
+        .collect()
+}
+
+impl Scope {
+    pub fn new(config: &PolicyConfig, target: &Target) -> Self {
+        Self {
+            users: target.users.clone(),
+            version: target.version.clone(),
+            groups: keys(config.groups.as_ref()),
+            hosts: keys(config.hosts.as_ref()),
+            tags: keys(config.tag_owners.as_ref()),
+            postures: keys(config.postures.as_ref()),
+        }
+    }
+
+    fn is_user(&self, name: &str) -> bool {
+        self.users
+            .as_ref()
+            .is_none_or(|users| users.contains(name) || users.contains(name.trim_end_matches('@')))
+    }
+}
+
+struct Validator<'a> {
+    scope: &'a Scope,
+    errors: Vec<FieldError>,
+}
+
+impl Validator<'_> {
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    /// checks a user, group or tag that owns or approves something
+    fn owner(&mut self, path: &str, owner: &str) {
+        if let Some(group) = owner.strip_prefix("group:") {
+            if group.is_empty() || !self.scope.groups.contains(owner) {
+                self.error(path, format!("group '{owner}' is not defined"));
+            }
+        } else if owner.starts_with("tag:") {
+            if !self.scope.tags.contains(owner) {
+                self.error(path, format!("tag '{owner}' is not defined in tagOwners"));
+            }
+        } else if !self.scope.is_user(owner) {
+            self.error(path, format!("unknown user '{owner}'"));
+        }
+    }
+
+    /// checks that the instance understands an autogroup, older versions only know
+    /// autogroup:internet
+    fn autogroup(&mut self, path: &str, alias: &str) {
+        if !alias.starts_with("autogroup:") || alias == "autogroup:internet" {
+            return;
+        }
+
+        if let Some(ref version) = self.scope.version
+            && !at_least(version, POLICY_V2_MIN_VERSION)
+        {
+            self.error(
+                path,
+                format!(
+                    "'{alias}' requires headscale {POLICY_V2_MIN_VERSION} or newer, the instance runs {version}"
+                ),
+            );
+        }
+    }
+
+    /// checks a source or destination of an acl or grant, without ports
+    fn alias(&mut self, path: &str, alias: &str, destination: bool) {
+        self.autogroup(path, alias);
+        match alias {
+            "*" | "autogroup:member" | "autogroup:tagged" => {}
+            "autogroup:internet" | "autogroup:self" if destination => {}
+            _ if alias.starts_with("autogroup:") => {
+                let side = if destination { "destination" } else { "source" };
+                self.error(
+                    path,
+                    format!("autogroup '{alias}' is not supported as {side}"),
+                );
+            }
+            _ if alias.starts_with("group:")
+                || alias.starts_with("tag:")
+                || alias.contains('@') =>
+            {
+                self.owner(path, alias)
+            }
+            _ if self.scope.hosts.contains(alias) => {}
+            _ if alias.parse::<IpAddr>().is_ok() || parse_cidr(alias).is_some() => {}
+            // users written by their bare name, as before headscale 0.26
+            _ if !alias.contains(':') && self.scope.is_user(alias) => {}
+            _ => self.error(
+                path,
+                format!("'{alias}' is not a user, group, tag, host or ip"),
+            ),
+        }
+    }
+
+    /// checks a comma separated list of ports and port ranges, or `*`
+    fn ports(&mut self, path: &str, ports: &str) {
+        if ports == "*" {
+            return;
+        }
+
+        for range in ports.split(',') {
+            match parse_port_range(range) {
+                Some((start, end)) if start > end => {
+                    self.error(path, format!("port range '{range}' ends before it starts"))
+                }
+                Some(_) => {}
+                None => self.error(
+                    path,
+                    format!("invalid port '{range}', expected a port or a range such as 8000-8080"),
+                ),
+            }
+        }
+    }
+
+    /// checks a destination of an acl, an alias followed by ports such as `tag:web:80,443`
+    fn destination(&mut self, path: &str, dst: &str) {
+        let Some((alias, ports)) = dst.rsplit_once(':') else {
+            return self.error(
+                path,
+                format!("destination '{dst}' is missing ports, such as '{dst}:*'"),
+            );
+        };
+
+        self.alias(path, alias, true);
+        self.ports(path, ports);
+    }
+
+    /// checks that definitions use the prefix of their kind and hosts point at addresses
+    fn definitions(&mut self, spec: &PolicySpec) {
+        for name in spec.groups.iter().flatten().map(|(name, _)| name) {
+            if name.strip_prefix("group:").is_none_or(str::is_empty) {
+                self.error(
+                    format!("spec.groups[{name}]"),
+                    format!("group '{name}' must start with 'group:'"),
+                );
+            }
+        }
+
+        for name in spec.tag_owners.iter().flatten().map(|(name, _)| name) {
+            if name.strip_prefix("tag:").is_none_or(str::is_empty) {
+                self.error(
+                    format!("spec.tagOwners[{name}]"),
+                    format!("tag '{name}' must start with 'tag:'"),
+                );
+            }
+        }
+
+        for (name, host) in spec.hosts.iter().flatten() {
+            if host.parse::<IpAddr>().is_err() && parse_cidr(host).is_none() {
+                self.error(
+                    format!("spec.hosts[{name}]"),
+                    format!("invalid ip or cidr '{host}'"),
+                );
+            }
+        }
+    }
+
+    fn acls(&mut self, spec: &PolicySpec) {
+        for (i, acl) in spec.acls.iter().enumerate() {
+            let path = format!("spec.acls[{i}]");
+
+            // headscale combines acls as a union of the connections they accept
+            if acl.action != Action::Accept {
+                self.error(
+                    format!("{path}.action"),
+                    "only 'accept' is supported, connections that no rule accepts are denied",
+                );
+            }
+
+            if acl.src.is_empty() {
+                self.error(format!("{path}.src"), "at least one source is required");
+            }
+            for (j, src) in acl.src.iter().enumerate() {
+                self.alias(&format!("{path}.src[{j}]"), src, false);
+            }
+
+            if acl.dst.is_empty() {
+                self.error(
+                    format!("{path}.dst"),
+                    "at least one destination is required",
+                );
+            }
+            for (j, dst) in acl.dst.iter().enumerate() {
+                self.destination(&format!("{path}.dst[{j}]"), dst);
+            }
+        }
+    }
+
+    fn tests(&mut self, spec: &PolicySpec) {
+        for (i, test) in spec.tests.iter().flatten().enumerate() {
+            let path = format!("spec.tests[{i}]");
+
+            if test.src == "*" || test.src.starts_with("autogroup:") {
+                self.error(
+                    format!("{path}.src"),
+                    "test source must be a user, group, tag, host or ip",
+                );
+            } else {
+                self.alias(&format!("{path}.src"), &test.src, false);
+            }
+
+            if test.accept.is_empty() && test.deny.is_empty() {
+                self.error(&path, "at least one accept or deny expectation is required");
+            }
+
+            let expectations = [("accept", &test.accept), ("deny", &test.deny)];
+            for (field, destinations) in expectations {
+                for (j, dst) in destinations.iter().enumerate() {
+                    let dst_path = format!("{path}.{field}[{j}]");
+                    match dst.rsplit_once(':') {
+                        Some((alias, port)) if parse_port(port).is_some() => {
+                            self.alias(&dst_path, alias, true)
+                        }
+                        _ => self.error(
+                            &dst_path,
+                            format!("'{dst}' must be a destination with a single port, such as 'tag:web:443'"),
+                        ),
+                    }
+                }
+            }
+        }
+    }
+
+    /// checks the source of an ssh rule, these are the owners of the connecting nodes
+    fn ssh_source(&mut self, path: &str, src: &str) {
+        self.autogroup(path, src);
+        match src {
+            "autogroup:member" | "autogroup:tagged" => {}
+            "*" => self.error(path, "wildcard sources are not supported for ssh"),
+            _ if src.starts_with("autogroup:") => self.error(
+                path,
+                format!("autogroup '{src}' is not supported as ssh source"),
+            ),
+            _ => self.owner(path, src),
+        }
+    }
+
+    fn ssh(&mut self, spec: &PolicySpec) {
+        for (i, rule) in spec.ssh.iter().flatten().enumerate() {
+            let path = format!("spec.ssh[{i}]");
+
+            if rule.src.is_empty() {
+                self.error(format!("{path}.src"), "at least one source is required");
+            }
+            for (j, src) in rule.src.iter().enumerate() {
+                self.ssh_source(&format!("{path}.src[{j}]"), src);
+            }
+
+            if rule.dst.is_empty() {
+                self.error(
+                    format!("{path}.dst"),
+                    "at least one destination is required",
+                );
+            }
+            for (j, dst) in rule.dst.iter().enumerate() {
+                let dst_path = format!("{path}.dst[{j}]");
+                if dst == "autogroup:self" {
+                    self.autogroup(&dst_path, dst);
+                    // nodes can only be reached by their own user
+                    if rule
+                        .src
+                        .iter()
+                        .any(|src| src.starts_with("tag:") || src == "autogroup:tagged")
+                    {
+                        self.error(
+                            &dst_path,
+                            "autogroup:self can't be combined with tagged sources",
+                        );
+                    }
+                } else if dst.starts_with("tag:") {
+                    if !self.scope.tags.contains(dst) {
+                        self.error(
+                            &dst_path,
+                            format!("tag '{dst}' is not defined in tagOwners"),
+                        );
+                    }
+                } else {
+                    self.error(
+                        &dst_path,
+                        format!(
+                            "invalid destination '{dst}', only autogroup:self and tags are allowed"
+                        ),
+                    );
+                }
+            }
+
+            if rule.users.is_empty() {
+                self.error(format!("{path}.users"), "at least one user is required");
+            }
+            for (j, user) in rule.users.iter().enumerate() {
+                let valid = match user.strip_prefix("autogroup:") {
+                    Some(group) => group == "nonroot",
+                    None => !user.is_empty() && !user.contains(char::is_whitespace),
+                };
+                if !valid {
+                    self.error(
+                        format!("{path}.users[{j}]"),
+                        format!("invalid ssh user '{user}'"),
+                    );
+                }
+            }
+
+            match (&rule.action, rule.check_period.as_deref()) {
+                (SshAction::Accept, Some(_)) => self.error(
+                    format!("{path}.checkPeriod"),
+                    "checkPeriod is only allowed for the check action",
+                ),
+                (SshAction::Check, Some(period)) if period != "always" && !is_duration(period) => {
+                    self.error(
+                        format!("{path}.checkPeriod"),
+                        format!("invalid duration '{period}'"),
+                    )
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn grants(&mut self, spec: &PolicySpec) {
+        let Some(ref grants) = spec.grants else {
+            return;
+        };
+
+        if let Some(ref version) = self.scope.version
+            && !at_least(version, GRANTS_MIN_VERSION)
+        {
+            self.error(
+                "spec.grants",
+                format!(
+                    "grants require headscale {GRANTS_MIN_VERSION} or newer, the instance runs {version}"
+                ),
+            );
+        }
+
+        for (i, grant) in grants.iter().enumerate() {
+            let path = format!("spec.grants[{i}]");
+            if grant.src.is_empty() {
+                self.error(format!("{path}.src"), "at least one source is required");
+            }
+            if grant.dst.is_empty() {
+                self.error(
+                    format!("{path}.dst"),
+                    "at least one destination is required",
+                );
+            }
+            for (j, src) in grant.src.iter().enumerate() {
+                self.alias(&format!("{path}.src[{j}]"), src, false);
+            }
+            for (j, dst) in grant.dst.iter().enumerate() {
+                self.alias(&format!("{path}.dst[{j}]"), dst, true);
+            }
+            for (j, ip) in grant.ip.iter().enumerate() {
+                let ip_path = format!("{path}.ip[{j}]");
+                match ip.split_once(':') {
+                    Some(("", _)) => self.error(&ip_path, format!("'{ip}' is missing a protocol")),
+                    Some((_, ports)) => self.ports(&ip_path, ports),
+                    None => self.ports(&ip_path, ip),
+                }
+            }
+            if grant.ip.is_empty() && grant.app.as_ref().is_none_or(|app| app.is_empty()) {
+                self.error(&path, "a grant requires ip or app capabilities");
+            }
+        }
+    }
+
+    fn postures(&mut self, spec: &PolicySpec) {
+        for (name, conditions) in spec.postures.iter().flatten() {
+            let path = format!("spec.postures[{name}]");
+            if !name.starts_with("posture:") {
+                self.error(
+                    &path,
+                    format!("posture '{name}' must start with 'posture:'"),
+                );
+            }
+            if conditions.is_empty() {
+                self.error(&path, "at least one condition is required");
+            }
+            for (i, condition) in conditions.iter().enumerate() {
+                if condition.trim().is_empty() {
+                    self.error(format!("{path}[{i}]"), "condition is empty");
+                }
+            }
+        }
+
+        for (i, acl) in spec.acls.iter().enumerate() {
+            for (j, posture) in acl.src_posture.iter().flatten().enumerate() {
+                if !self.scope.postures.contains(posture) {
+                    self.error(
+                        format!("spec.acls[{i}].srcPosture[{j}]"),
+                        format!("posture '{posture}' is not defined"),
+                    );
+                }
+            }
+        }
+    }
+
+    fn auto_approvers(&mut self, spec: &PolicySpec) {
+        let Some(ref auto_approvers) = spec.auto_approvers else {
+            return;
+        };
+
+        for (route, approvers) in &auto_approvers.routes {
+            let path = format!("spec.autoApprovers.routes[{route}]");
+            if parse_cidr(route).is_none() {
+                self.error(&path, format!("invalid cidr '{route}'"));
+            }
+            for (i, approver) in approvers.iter().enumerate() {
+                self.owner(&format!("{path}[{i}]"), approver);
+            }
+        }
+
+        for (i, approver) in auto_approvers.exit_node.iter().enumerate() {
+            self.owner(&format!("spec.autoApprovers.exitNode[{i}]"), approver);
+        }
+    }
+}
+
+/// whether `duration` is a go duration such as `12h` or `1h30m`
+pub fn is_duration(duration: &str) -> bool {
+    const UNITS: [&str; 7] = ["ns", "us", "µs", "ms", "s", "m", "h"];
+
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        // a number with at most one decimal point, such as `1.5`, `1.` or `.5`
+        let number = &rest[..digits];
+        if number.matches('.').count() > 1 || !number.contains(|c: char| c.is_ascii_digit()) {
+            return false;
+        }
+        rest = &rest[digits..];
+
+        // longest unit first, so `ms` isn't read as `m`
+        let Some(unit) = UNITS
+            .iter()
+            .filter(|unit| rest.starts_with(*unit))
+            .max_by_key(|unit| unit.len())
+        else {
+            return false;
+        };
+        rest = &rest[unit.len()..];
+    }
+
+    !duration.is_empty()
+}
+
+/// parses a port or a range of ports such as `8000-8080`
+pub(super) fn parse_port_range(range: &str) -> Option<(u16, u16)> {
+    match range.split_once('-') {
+        Some((start, end)) => Some((parse_port(start)?, parse_port(end)?)),
+        None => {
+            let port = parse_port(range)?;
+            Some((port, port))
+        }
+    }
+}
+
+/// parses a single port, port 0 can't be connected to
+pub(super) fn parse_port(port: &str) -> Option<u16> {
+    port.parse().ok().filter(|&port| port != 0)
+}
+
+/// parses an ip prefix such as `10.0.0.0/8`, a bare address is not a prefix
+pub fn parse_cidr(cidr: &str) -> Option<(IpAddr, u8)> {
+    let (addr, bits) = cidr.split_once('/')?;
+    let addr: IpAddr = addr.parse().ok()?;
+    let bits: u8 = bits.parse().ok()?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+
+    (bits <= max).then_some((addr, bits))
+}
+
+/// validates a policy against the names defined by all policies of its headscale instance
+pub fn validate(spec: &PolicySpec, scope: &Scope) -> Vec<FieldError> {
+    let mut validator = Validator {
+        scope,
+        errors: Vec::new(),
+    };
+
+    validator.definitions(spec);
+    validator.acls(spec);
+    validator.auto_approvers(spec);
+    validator.ssh(spec);
+    validator.grants(spec);
+    validator.postures(spec);
+    validator.tests(spec);
+
+    validator.errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// validation errors of a spec that only refers to names it defines itself
+    fn errors(spec: serde_json::Value) -> Vec<String> {
+        let spec: PolicySpec = serde_json::from_value(spec).unwrap();
+        let config = PolicyConfig {
+            groups: spec.groups.clone(),
+            hosts: spec.hosts.clone(),
+            tag_owners: spec.tag_owners.clone(),
+            ..Default::default()
+        };
+        let scope = Scope::new(&config, &Target::default());
+
+        validate(&spec, &scope)
+            .into_iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn parses_ports_and_ranges() {
+        assert_eq!(parse_port_range("443"), Some((443, 443)));
+        assert_eq!(parse_port_range("8000-8080"), Some((8000, 8080)));
+        assert_eq!(parse_port_range("65535"), Some((65535, 65535)));
+        assert_eq!(parse_port_range("65536"), None);
+        assert_eq!(parse_port_range("http"), None);
+        assert_eq!(parse_port_range("80-"), None);
+    }
+
+    #[test]
+    fn rejects_port_zero() {
+        assert_eq!(parse_port_range("0"), None);
+        assert_eq!(parse_port_range("0-80"), None);
+        assert_eq!(parse_port("0"), None);
+    }
+
+    #[test]
+    fn validates_destination_ports() {
+        let spec = |dst: &str| {
+            serde_json::json!({
+                "headscaleRef": { "name": "example" },
+                "hosts": { "web": "10.0.0.1" },
+                "acls": [{ "action": "accept", "src": ["*"], "dst": [dst] }],
+            })
+        };
+
+        assert!(errors(spec("web:*")).is_empty());
+        assert!(errors(spec("web:80,443,8000-8080")).is_empty());
+        assert_eq!(errors(spec("web:0")).len(), 1);
+        assert_eq!(errors(spec("web:8080-8000")).len(), 1);
+        assert_eq!(errors(spec("web:http")).len(), 1);
+    }
+
+    #[test]
+    fn rejects_test_destinations_on_port_zero() {
+        let spec = serde_json::json!({
+            "headscaleRef": { "name": "example" },
+            "hosts": { "web": "10.0.0.1" },
+            "tests": [{ "src": "web", "accept": ["web:0"] }],
+        });
+
+        assert_eq!(errors(spec).len(), 1);
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(
+            parse_cidr("10.0.0.0/8"),
+            Some(("10.0.0.0".parse().unwrap(), 8))
+        );
+        assert_eq!(
+            parse_cidr("fd7a:115c:a1e0::/48"),
+            Some(("fd7a:115c:a1e0::".parse().unwrap(), 48))
+        );
+        assert_eq!(parse_cidr("10.0.0.0/33"), None);
+        assert_eq!(parse_cidr("::/129"), None);
+        assert_eq!(parse_cidr("10.0.0.1"), None);
+    }
+
+    #[test]
+    fn accepts_go_durations() {
+        for duration in ["12h", "1h30m", "1.5h", "90s", "500ms", "1.h", ".5h", "10µs"] {
+            assert!(is_duration(duration), "{duration}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for duration in ["", "h", "12", "1.5.5h", "..5h", ".h", "1d", "1h30", "-1h"] {
+            assert!(!is_duration(duration), "{duration}");
+        }
+    }
+}
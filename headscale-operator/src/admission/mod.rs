@@ -7,6 +7,7 @@ pub use kubus::admission;
 use serde::de::DeserializeOwned;
 
 pub mod headscale;
+pub mod policy;
 pub mod sidecar;
 
 pub trait AdmissionRequestExt {
@@ -0,0 +1,128 @@
+use kube::{Api, Client, Resource, ResourceExt, api::ListParams};
+use tokio::sync::OnceCell;
+
+use super::*;
+
+use crate::acl;
+use crate::crds::group::Group;
+use crate::crds::policy::Policy;
+use crate::crds::user::User;
+
+/// shared between requests, so the webhook doesn't set up a new client for every request
+static CLIENT: OnceCell<Client> = OnceCell::const_new();
+
+async fn client() -> Result<Client, Error> {
+    Ok(CLIENT.get_or_try_init(Client::try_default).await?.clone())
+}
+
+fn target(policy: &Policy) -> (String, String) {
+    let namespace = policy.namespace().unwrap_or_default();
+    policy.spec.headscale_ref.target(&namespace)
+}
+
+/// the users of the headscale instance of a policy
+async fn list_users(client: Client, policy: &Policy) -> Result<Vec<User>, Error> {
+    let headscale = target(policy);
+
+    let api = Api::<User>::all(client);
+    let users = api
+        .list(&ListParams::default())
+        .await?
+        .items
+        .into_iter()
+        .filter(|user| {
+            let namespace = user.namespace().unwrap_or_default();
+            user.spec.headscale_ref.target(&namespace) == headscale
+        })
+        .collect();
+
+    Ok(users)
+}
+
+/// the group resources of the headscale instance of a policy, ordered by namespace and name
+async fn list_groups(client: Client, policy: &Policy) -> Result<Vec<Group>, Error> {
+    let headscale = target(policy);
+
+    let api = Api::<Group>::all(client);
+    let mut groups: Vec<_> = api
+        .list(&ListParams::default())
+        .await?
+        .items
+        .into_iter()
+        .filter(|group| group.meta().deletion_timestamp.is_none())
+        .filter(|group| {
+            let namespace = group.namespace().unwrap_or_default();
+            group.spec.headscale_ref.target(&namespace) == headscale
+        })
+        .collect();
+
+    groups.sort_by_key(|group| (group.namespace(), group.name_any()));
+
+    Ok(groups)
+}
+
+/// the other policies of the same headscale instance
+async fn list_siblings(client: Client, policy: &Policy) -> Result<Vec<Policy>, Error> {
+    let headscale = target(policy);
+
+    let api = Api::<Policy>::all(client);
+    let policies = api
+        .list(&ListParams::default())
+        .await?
+        .items
+        .into_iter()
+        .filter(|other| other.meta().deletion_timestamp.is_none())
+        .filter(|other| target(other) == headscale)
+        .collect();
+
+    Ok(policies)
+}
+
+#[admission(validating)]
+pub async fn validate(req: &AdmissionRequest<DynamicObject>) -> Result<AdmissionResponse, Error> {
+    let res = AdmissionResponse::from(req);
+    if !Policy::is(&req.kind) {
+        return Ok(res);
+    }
+
+    // deletions come without an object, there is nothing to validate
+    let Some(ref object) = req.object else {
+        return Ok(res);
+    };
+    let policy: Policy = parse_crd(object)?;
+
+    let client = client().await?;
+    let users = list_users(client.clone(), &policy).await?;
+    let policy = match policy.resolve_raw(&client).await {
+        Ok(policy) => policy.expand_selectors(&client, &users).await?,
+        Err(error) => return Ok(res.deny(error.to_string())),
+    };
+
+    // siblings that can't be resolved are left out of the acl anyway
+    let mut siblings = Vec::new();
+    for sibling in list_siblings(client.clone(), &policy).await? {
+        if let Ok(sibling) = sibling.resolve_raw(&client).await {
+            siblings.push(sibling.expand_selectors(&client, &users).await?);
+        }
+    }
+
+    let groups = list_groups(client.clone(), &policy)
+        .await?
+        .iter()
+        .map(|group| group.definition(&users))
+        .collect();
+
+    let (errors, warnings) = acl::check(&policy, &siblings, groups);
+    let errors: Vec<_> = errors.iter().map(|error| error.to_string()).collect();
+    if !errors.is_empty() {
+        return Ok(res.deny(errors.join("; ")));
+    }
+
+    // warnings are shown by kubectl without blocking the apply
+    let mut res = res;
+    if !warnings.is_empty() {
+        res.warnings = Some(warnings.iter().map(|warning| warning.to_string()).collect());
+    }
+
+    Ok(res)
+}
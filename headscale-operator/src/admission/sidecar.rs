@@ -11,6 +11,14 @@ const ANNOTATION_EXTRA_ARGS: &str = "headscale.juliamertz.dev/tailscale-extra-ar
 const ANNOTATION_IMAGE: &str = "headscale.juliamertz.dev/tailscale-image";
 const ANNOTATION_AUTH_SECRET: &str = "headscale.juliamertz.dev/tailscale-auth-secret";
 const ANNOTATION_RESOURCES: &str = "headscale.juliamertz.dev/tailscale-resources";
+pub const ANNOTATION_USER: &str = "headscale.juliamertz.dev/tailscale-user";
+pub const ANNOTATION_TAGS: &str = "headscale.juliamertz.dev/tailscale-tags";
+/// name of the pod scoped secret holding the ephemeral key, set by the webhook
+pub const ANNOTATION_EPHEMERAL_SECRET: &str = "headscale.juliamertz.dev/tailscale-ephemeral-secret";
+pub const LABEL_EPHEMERAL_KEY: &str = "headscale.juliamertz.dev/tailscale-ephemeral-key";
+
+const STATE_VOLUME: &str = "tailscale-state";
+const STATE_DIR: &str = "/var/lib/tailscale";
 
 fn should_inject(req: &AdmissionRequest<DynamicObject>) -> bool {
     Pod::is(&req.kind)
@@ -20,11 +28,101 @@ fn should_inject(req: &AdmissionRequest<DynamicObject>) -> bool {
             .unwrap_or(false)
 }
 
+/// where the sidecar gets its preauth key from
+enum AuthKeySource<'a> {
+    /// a secret managed by the user, usually holding a reusable key
+    Secret(&'a str),
+    /// a single use key minted by the operator for this pod
+    Ephemeral(String),
+}
+
+impl AuthKeySource<'_> {
+    fn secret_name(&self) -> &str {
+        match self {
+            Self::Secret(name) => name,
+            Self::Ephemeral(name) => name,
+        }
+    }
+}
+
+/// escapes a key for use as a json pointer segment
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// adds an entry to a map under `/metadata`, creating the map if it doesn't exist yet
+fn add_metadata_entry(
+    req: &AdmissionRequest<DynamicObject>,
+    map: &str,
+    key: &str,
+    value: &str,
+) -> Result<PatchOperation, Error> {
+    let exists = req
+        .object
+        .as_ref()
+        .map(|obj| match map {
+            "labels" => obj.metadata.labels.is_some(),
+            _ => obj.metadata.annotations.is_some(),
+        })
+        .unwrap_or(false);
+
+    Ok(if exists {
+        PatchOperation::Add(AddOperation {
+            path: format!("/metadata/{map}/{}", escape_pointer(key)).parse()?,
+            value: serde_json::json!(value),
+        })
+    } else {
+        PatchOperation::Add(AddOperation {
+            path: format!("/metadata/{map}").parse()?,
+            value: serde_json::json!({ key: value }),
+        })
+    })
+}
+
+/// generates the name of the pod scoped secret, the pod name isn't known yet when `generateName`
+/// is used
+fn ephemeral_secret_name() -> String {
+    use rand::RngCore;
+
+    let mut buf = [0u8; 5];
+    rand::rng().fill_bytes(&mut buf);
+    format!("tailscale-authkey-{}", hex::encode(buf))
+}
+
+fn build_ephemeral_patch(
+    req: &AdmissionRequest<DynamicObject>,
+    secret_name: &str,
+) -> Result<Vec<PatchOperation>, Error> {
+    let has_volumes = req
+        .object
+        .as_ref()
+        .is_some_and(|obj| obj.data["spec"]["volumes"].is_array());
+
+    let volume = serde_json::json!({ "name": STATE_VOLUME, "emptyDir": {} });
+    let volume = if has_volumes {
+        PatchOperation::Add(AddOperation {
+            path: "/spec/volumes/-".parse()?,
+            value: volume,
+        })
+    } else {
+        PatchOperation::Add(AddOperation {
+            path: "/spec/volumes".parse()?,
+            value: serde_json::json!([volume]),
+        })
+    };
+
+    Ok(vec![
+        add_metadata_entry(req, "annotations", ANNOTATION_EPHEMERAL_SECRET, secret_name)?,
+        add_metadata_entry(req, "labels", LABEL_EPHEMERAL_KEY, "true")?,
+        volume,
+    ])
+}
+
 fn build_sidecar_patch(
     extra_args: Option<&str>,
     image: Option<&str>,
     resources: Option<&str>,
-    auth_secret: &str,
+    auth_key: &AuthKeySource,
 ) -> Result<JsonPatch, Error> {
     let resources = resources
         .map(serde_json::from_str::<ResourceRequirements>)
@@ -37,7 +135,7 @@ fn build_sidecar_patch(
             }))
         })?;
 
-    let container = serde_json::json!({
+    let mut container = serde_json::json!({
         "name": "tailscale-sidecar",
         "image": image.unwrap_or(&IMAGES.tailscale),
         "securityContext": {
@@ -55,7 +153,7 @@ fn build_sidecar_patch(
                 "name": "TS_AUTHKEY",
                 "valueFrom": {
                     "secretKeyRef": {
-                        "name": auth_secret,
+                        "name": auth_key.secret_name(),
                         "key": "authkey"
                     }
                 }
@@ -80,6 +178,17 @@ fn build_sidecar_patch(
         "resources": resources
     });
 
+    // single use keys can't be used again when the container restarts, so the node state has to
+    // outlive the container
+    if let AuthKeySource::Ephemeral(_) = auth_key {
+        container["env"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({ "name": "TS_STATE_DIR", "value": STATE_DIR }));
+        container["volumeMounts"] =
+            serde_json::json!([{ "name": STATE_VOLUME, "mountPath": STATE_DIR }]);
+    }
+
     Ok(JsonPatch(vec![PatchOperation::Add(AddOperation {
         path: "/spec/containers/-".parse()?,
         value: container,
@@ -92,12 +201,50 @@ pub async fn mutate(req: &AdmissionRequest<DynamicObject>) -> Result<AdmissionRe
         let extra_args = req.get_annotation(ANNOTATION_EXTRA_ARGS);
         let image = req.get_annotation(ANNOTATION_IMAGE);
         let resources = req.get_annotation(ANNOTATION_RESOURCES);
-        let Some(auth_secret) = req.get_annotation(ANNOTATION_AUTH_SECRET) else {
-            let reason = format!("missing required '{ANNOTATION_AUTH_SECRET}' annotation");
-            return Ok(AdmissionResponse::from(req).deny(reason));
+
+        let auth_key = match (
+            req.get_annotation(ANNOTATION_AUTH_SECRET),
+            req.get_annotation(ANNOTATION_USER),
+        ) {
+            (Some(secret), None) => AuthKeySource::Secret(secret),
+            (None, Some(user)) => {
+                if user.contains('/') {
+                    let reason = format!(
+                        "'{ANNOTATION_USER}' must name a user in the namespace of the pod, got '{user}'"
+                    );
+                    return Ok(AdmissionResponse::from(req).deny(reason));
+                }
+
+                let tags = req.get_annotation(ANNOTATION_TAGS).unwrap_or_default();
+                if let Some(tag) = tags
+                    .split(',')
+                    .map(str::trim)
+                    .find(|tag| !tag.is_empty() && !tag.starts_with("tag:"))
+                {
+                    let reason = format!("invalid tag '{tag}', tags must start with 'tag:'");
+                    return Ok(AdmissionResponse::from(req).deny(reason));
+                }
+
+                AuthKeySource::Ephemeral(ephemeral_secret_name())
+            }
+            (Some(_), Some(_)) => {
+                let reason = format!(
+                    "'{ANNOTATION_AUTH_SECRET}' and '{ANNOTATION_USER}' annotations are mutually exclusive"
+                );
+                return Ok(AdmissionResponse::from(req).deny(reason));
+            }
+            (None, None) => {
+                let reason = format!(
+                    "missing required '{ANNOTATION_AUTH_SECRET}' or '{ANNOTATION_USER}' annotation"
+                );
+                return Ok(AdmissionResponse::from(req).deny(reason));
+            }
         };
 
-        let patch = build_sidecar_patch(extra_args, image, resources, auth_secret)?;
+        let mut patch = build_sidecar_patch(extra_args, image, resources, &auth_key)?;
+        if let AuthKeySource::Ephemeral(ref secret_name) = auth_key {
+            patch.0.extend(build_ephemeral_patch(req, secret_name)?);
+        }
 
         Ok(AdmissionResponse::from(req).with_patch(patch)?)
     } else {
@@ -0,0 +1,33 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition;
+
+use crate::crds::user::UserRef;
+use crate::handlers::HeadscaleRef;
+
+use super::*;
+
+/// a group of users in the acl, named `group:<name of the resource>`
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    group = "headscale.juliamertz.dev",
+    version = "v1alpha1",
+    kind = "Group",
+    status = "GroupStatus",
+    printcolumn = r#"{"name": "Resolved", "type": "string", "jsonPath": ".status.conditions[?(@.type==\"Resolved\")].status"}"#,
+    namespaced
+)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupSpec {
+    pub headscale_ref: HeadscaleRef,
+    #[serde(default)]
+    pub members: Vec<UserRef>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupStatus {
+    /// headscale users of the members, as they appear in the acl
+    #[serde(default)]
+    pub members: Vec<String>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
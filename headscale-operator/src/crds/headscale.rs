@@ -1,5 +1,6 @@
 use k8s_openapi::NamespaceResourceScope;
 
+use crate::crds::policy::PolicyConfig;
 use crate::helper::IMAGES;
 
 use super::*;
@@ -39,6 +40,24 @@ pub struct TLSOptions {
     pub existing_secret: Option<String>,
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum DefaultPolicyMode {
+    #[default]
+    AllowAll,
+    DenyAll,
+    Custom,
+}
+
+/// acl used when no policy references the headscale instance
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DefaultPolicy {
+    pub mode: DefaultPolicyMode,
+    /// baseline acl for the `custom` mode
+    pub custom: Option<PolicyConfig>,
+}
+
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[kube(
     group = "headscale.juliamertz.dev",
@@ -59,6 +78,8 @@ pub struct HeadscaleSpec {
     #[serde(default)]
     pub config_manager: ConfigManagerOptions,
     pub tls: TLSOptions,
+    #[serde(default)]
+    pub default_policy: DefaultPolicy,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
@@ -3,22 +3,50 @@ pub use schemars::JsonSchema;
 pub use serde::{Deserialize, Serialize};
 pub use serde_with::skip_serializing_none;
 
+pub mod group;
 pub mod headscale;
 pub mod policy;
 pub mod preauth_key;
+pub mod preauth_key_pool;
 pub mod user;
 
+pub use group::Group;
 pub use headscale::Headscale;
 pub use policy::Policy;
 pub use preauth_key::PreauthKey;
+pub use preauth_key_pool::PreauthKeyPool;
 
 /// serialized timestamp format that headscale uses
-#[derive(Debug, Default, Clone, Serialize, Deserialize, JsonSchema)]
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
 pub struct Timestamp {
     seconds: u64,
+    #[serde(default)]
     nanos: u64,
 }
 
+impl Timestamp {
+    pub fn now() -> Self {
+        let elapsed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Self {
+            seconds: elapsed.as_secs(),
+            nanos: elapsed.subsec_nanos().into(),
+        }
+    }
+
+    /// the timestamp `seconds` later
+    pub fn after(&self, seconds: u64) -> Self {
+        Self {
+            seconds: self.seconds + seconds,
+            nanos: self.nanos,
+        }
+    }
+}
+
 pub fn preserve_unknown_fields(_gen: &mut schemars::SchemaGenerator) -> schemars::Schema {
     schemars::json_schema!({ "x-kubernetes-preserve-unknown-fields": true })
 }
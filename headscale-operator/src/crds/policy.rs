@@ -4,22 +4,30 @@ use crate::handlers::HeadscaleRef;
 
 use std::collections::BTreeMap;
 
-#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+use k8s_openapi::NamespaceResourceScope;
+use k8s_openapi::api::core::v1::ConfigMapKeySelector;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, LabelSelector};
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum Action {
     Accept,
     Deny,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[skip_serializing_none]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct Acl {
     pub action: Action,
     pub src: Vec<String>,
     pub dst: Vec<String>,
+    /// postures the source node has to match for the rule to apply
+    pub src_posture: Option<Vec<String>>,
 }
 
-pub type Group = String;
-pub type Groups = BTreeMap<String, Vec<Group>>;
+pub type GroupMember = String;
+pub type Groups = BTreeMap<String, Vec<GroupMember>>;
 
 pub type TagOwner = String;
 pub type TagOwners = BTreeMap<String, Vec<TagOwner>>;
@@ -27,42 +35,187 @@ pub type TagOwners = BTreeMap<String, Vec<TagOwner>>;
 pub type Host = String;
 pub type Hosts = BTreeMap<String, Host>;
 
+/// groups of the users of the instance matching a label selector
+pub type GroupSelectors = BTreeMap<String, LabelSelector>;
+
+/// kind of object hosts are generated from
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum HostKind {
+    /// the load balancer ip of a service, or its cluster ip
+    Service,
+    /// the internal ip of a node
+    Node,
+    /// the pod cidr of a node
+    NodePodCIDR,
+}
+
+/// hosts generated from the objects matching a label selector, named `{prefix}{name}`
+#[skip_serializing_none]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HostSelector {
+    pub kind: HostKind,
+    pub selector: LabelSelector,
+    #[serde(default)]
+    pub prefix: String,
+    /// namespace of the services, defaults to the namespace of the policy
+    pub namespace: Option<String>,
+}
+
+/// device posture conditions, such as `node:os IN ['linux', 'macos']` or `node:tsVersion >= '1.60'`
+pub type Posture = Vec<String>;
+pub type Postures = BTreeMap<String, Posture>;
+
+/// routes and exit nodes that are approved automatically when advertised by a node owned by one of
+/// the approvers
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AutoApprovers {
+    /// approvers by route cidr
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub routes: BTreeMap<String, Vec<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exit_node: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SshAction {
+    Accept,
+    /// requires the user to re-authenticate every `checkPeriod`
+    Check,
+}
+
+/// tailscale ssh access rule
+#[skip_serializing_none]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SshRule {
+    pub action: SshAction,
+    pub src: Vec<String>,
+    pub dst: Vec<String>,
+    /// local users that may be logged in as
+    pub users: Vec<String>,
+    /// how often a `check` rule requires re-authentication, a go duration or `always`
+    pub check_period: Option<String>,
+}
+
+/// access rule in the grants syntax, allowing network access through `ip` and application
+/// capabilities through `app`
+#[skip_serializing_none]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Grant {
+    pub src: Vec<String>,
+    pub dst: Vec<String>,
+    /// protocols and ports, such as `*`, `443` or `tcp:8000-8080`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ip: Vec<String>,
+    /// capabilities by name, the values are passed to the application as-is
+    #[serde(default)]
+    #[schemars(schema_with = "preserve_unknown_fields")]
+    pub app: Option<BTreeMap<String, Vec<serde_json::Value>>>,
+}
+
+/// expectations for the acl, evaluated by the operator and headscale before the acl is applied
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AclTest {
+    /// user, group member, tag or ip the connection originates from
+    pub src: String,
+    /// destinations with a port, such as `tag:web:443`, that must be reachable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accept: Vec<String>,
+    /// destinations with a port that must not be reachable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+}
+
+/// a policy in the hujson format, inline or from a configmap in the namespace of the policy
+#[skip_serializing_none]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RawPolicy {
+    pub inline: Option<String>,
+    pub config_map_key_ref: Option<ConfigMapKeySelector>,
+}
+
+/// top level acl options the operator doesn't know, passed through as-is
+pub type ExtraOptions = BTreeMap<String, serde_json::Value>;
+
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[kube(
     group = "headscale.juliamertz.dev",
     version = "v1alpha1",
     kind = "Policy",
+    status = "PolicyStatus",
+    printcolumn = r#"{"name": "Accepted", "type": "string", "jsonPath": ".status.conditions[?(@.type==\"Accepted\")].status"}"#,
+    printcolumn = r#"{"name": "Synced", "type": "string", "jsonPath": ".status.conditions[?(@.type==\"Synced\")].status"}"#,
     namespaced
 )]
 #[serde(rename_all = "camelCase")]
 pub struct PolicySpec {
     pub headscale_ref: HeadscaleRef,
+    /// hujson policy used instead of the fields below
+    pub raw: Option<RawPolicy>,
     pub groups: Option<Groups>,
+    pub group_selectors: Option<GroupSelectors>,
     pub hosts: Option<Hosts>,
+    pub host_selectors: Option<Vec<HostSelector>>,
     pub tag_owners: Option<TagOwners>,
+    pub postures: Option<Postures>,
+    #[serde(default)]
     pub acls: Vec<Acl>,
-    // TODO:
-    // pub auto_approvers: Option<AutoApproverPolicy>,
-    // pub ssh: Option<Vec<Ssh>>
+    pub auto_approvers: Option<AutoApprovers>,
+    pub ssh: Option<Vec<SshRule>>,
+    pub grants: Option<Vec<Grant>>,
+    pub tests: Option<Vec<AclTest>>,
+    /// only set from a raw policy
+    #[serde(flatten)]
+    #[schemars(skip)]
+    pub extra: ExtraOptions,
+}
+
+impl k8s_openapi::Resource for Policy {
+    const API_VERSION: &'static str = "headscale.juliamertz.dev/v1alpha1";
+    const GROUP: &'static str = "headscale.juliamertz.dev";
+    const KIND: &'static str = "Policy";
+    const VERSION: &'static str = "v1alpha1";
+    const URL_PATH_SEGMENT: &'static str = "policies";
+    type Scope = NamespaceResourceScope;
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyStatus {
+    /// generation of the policy last written to the acl
+    pub observed_generation: Option<i64>,
+    /// hash of the acl the policy was last written to
+    pub hash: Option<String>,
+    /// configmap the acl is written to
+    pub config_map: Option<String>,
+    /// problems that don't keep the policy out of the acl, such as deprecated syntax
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 #[skip_serializing_none]
 pub struct PolicyConfig {
     pub groups: Option<Groups>,
     pub hosts: Option<Hosts>,
     pub tag_owners: Option<TagOwners>,
+    pub postures: Option<Postures>,
+    #[serde(default)]
     pub acls: Vec<Acl>,
-}
-
-impl From<PolicySpec> for PolicyConfig {
-    fn from(policy: PolicySpec) -> Self {
-        Self {
-            groups: policy.groups,
-            hosts: policy.hosts,
-            tag_owners: policy.tag_owners,
-            acls: policy.acls,
-        }
-    }
+    pub auto_approvers: Option<AutoApprovers>,
+    pub ssh: Option<Vec<SshRule>>,
+    pub grants: Option<Vec<Grant>>,
+    pub tests: Option<Vec<AclTest>>,
+    #[serde(flatten)]
+    #[schemars(skip)]
+    pub extra: ExtraOptions,
 }
@@ -1,14 +1,72 @@
 use super::*;
 
+use std::collections::BTreeMap;
+
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, LabelSelector};
+
+use crate::crds::headscale::HeadscaleRef;
 use crate::crds::user::UserRef;
 
+fn default_tailscaled_config_key() -> String {
+    "tailscaled.json".to_string()
+}
+
+/// additional entries and metadata for the secret a preauth key is written to
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SecretTemplate {
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+    /// adds a `loginServer` entry containing the server url of the headscale instance
+    pub login_server: bool,
+    /// adds a `TS_EXTRA_ARGS` entry containing `--login-server` followed by these arguments
+    pub extra_args: Option<String>,
+    /// adds a tailscaled configuration file entry
+    pub tailscaled_config: Option<TailscaledConfigTemplate>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TailscaledConfigTemplate {
+    /// name of the secret entry the configuration is written to
+    #[serde(default = "default_tailscaled_config_key")]
+    pub key: String,
+    pub hostname: Option<String>,
+    pub accept_dns: Option<bool>,
+    pub accept_routes: Option<bool>,
+    #[serde(default)]
+    pub advertise_routes: Vec<String>,
+    pub run_ssh_server: Option<bool>,
+}
+
+/// tailscaled configuration file format, see `ipn.ConfigVAlpha` in tailscale
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+#[skip_serializing_none]
+pub struct TailscaledConfig {
+    pub version: &'static str,
+    #[serde(rename = "ServerURL")]
+    pub server_url: String,
+    pub auth_key: String,
+    pub hostname: Option<String>,
+    #[serde(rename = "AcceptDNS")]
+    pub accept_dns: Option<bool>,
+    pub accept_routes: Option<bool>,
+    pub advertise_routes: Option<Vec<String>>,
+    #[serde(rename = "RunSSHServer")]
+    pub run_ssh_server: Option<bool>,
+}
+
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[kube(
     group = "headscale.juliamertz.dev",
     version = "v1alpha1",
     kind = "PreauthKey",
     status = "PreauthKeyStatus",
-    namespaced
+    namespaced,
+    printcolumn = r#"{"name": "Phase", "type": "string", "jsonPath": ".status.phase"}"#,
+    printcolumn = r#"{"name": "Used", "type": "boolean", "jsonPath": ".status.used"}"#,
+    printcolumn = r#"{"name": "Expired", "type": "boolean", "jsonPath": ".status.expired"}"#
 )]
 #[serde(default, rename_all = "camelCase")]
 pub struct PreauthKeySpec {
@@ -17,6 +75,12 @@ pub struct PreauthKeySpec {
     pub expiration: String,
     pub target_secret: Option<String>,
     pub user: UserRef,
+    pub tags: Vec<String>,
+    pub secret_template: Option<SecretTemplate>,
+    /// namespaces the secret is replicated to, in addition to the namespace of the resource
+    pub target_namespaces: Vec<String>,
+    /// replicates the secret to every namespace matching this selector
+    pub namespace_selector: Option<LabelSelector>,
 }
 
 impl Default for PreauthKeySpec {
@@ -27,30 +91,141 @@ impl Default for PreauthKeySpec {
             expiration: "1h".to_string(),
             target_secret: None,
             user: Default::default(),
+            tags: Vec::new(),
+            secret_template: None,
+            target_namespaces: Vec::new(),
+            namespace_selector: None,
         }
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub enum PreauthKeyPhase {
+    /// no key has been issued yet
+    #[default]
+    Pending,
+    /// a key is being issued, it may exist in headscale without having been written to the secret
+    Issuing,
+    /// the key has been written to the target secret
+    Issued,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct PreauthKeyStatus {
-    pub id: u32,
-    pub user: crate::crds::user::UserStatus,
+    /// statuses written before phases were tracked are treated as issued
+    pub phase: Option<PreauthKeyPhase>,
+    /// when the current issuance was started, used to find keys leaked by an interrupted issuance
+    pub issuing_since: Option<Timestamp>,
+    pub id: Option<u32>,
+    pub user: Option<crate::crds::user::UserStatus>,
+    /// headscale instance the key was issued by, used to revoke the key after the user is gone
+    pub headscale: Option<HeadscaleRef>,
+    #[serde(default)]
     pub reusable: bool,
+    #[serde(default)]
     pub ephemeral: bool,
-    pub expiration: Timestamp,
-    pub created_at: Timestamp,
+    pub expiration: Option<Timestamp>,
+    pub created_at: Option<Timestamp>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// expiration duration from the spec the key was issued with
+    pub requested_expiration: Option<String>,
+    /// sha256 digest of the issued key, used to detect tampering with the target secret
+    pub key_hash: Option<String>,
+    /// whether a node was registered with the key
+    #[serde(default)]
+    pub used: bool,
+    #[serde(default)]
+    pub expired: bool,
+    /// nodes registered with the key
+    #[serde(default)]
+    pub nodes: Vec<RegisteredNode>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisteredNode {
+    pub id: u64,
+    pub hostname: String,
+    pub given_name: Option<String>,
+}
+
+impl PreauthKeyStatus {
+    pub fn phase(&self) -> PreauthKeyPhase {
+        match (&self.phase, self.id) {
+            (Some(phase), _) => phase.clone(),
+            (None, Some(_)) => PreauthKeyPhase::Issued,
+            (None, None) => PreauthKeyPhase::Pending,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct PreauthKeyData {
     pub id: u32,
     pub user: crate::crds::user::UserData,
+    #[serde(default)]
     pub key: String,
     #[serde(default)]
     pub reusable: bool,
     #[serde(default)]
     pub ephemeral: bool,
+    #[serde(default)]
+    pub used: bool,
     pub expiration: Timestamp,
     pub created_at: Timestamp,
+    #[serde(default)]
+    pub acl_tags: Vec<String>,
+}
+
+/// internal headscale data structure used for deserializing cli output
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeData {
+    pub id: u64,
+    #[serde(default)]
+    pub name: String,
+    pub given_name: Option<String>,
+    pub pre_auth_key: Option<NodePreauthKeyData>,
+    #[serde(default)]
+    pub ip_addresses: Vec<String>,
+    pub user: Option<NodeUserData>,
+    #[serde(default)]
+    pub forced_tags: Vec<String>,
+    #[serde(default)]
+    pub valid_tags: Vec<String>,
+    /// tags of the node on headscale versions that no longer split them into forced and valid
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl NodeData {
+    /// the identities acl rules match the node by, its tags or the user owning it when untagged
+    pub fn identities(&self) -> Vec<String> {
+        let tags = [&self.forced_tags, &self.valid_tags, &self.tags];
+        let mut identities: Vec<_> = tags.into_iter().flatten().cloned().collect();
+        identities.sort();
+        identities.dedup();
+
+        if identities.is_empty()
+            && let Some(user) = &self.user
+        {
+            identities.push(format!("{}@", user.name));
+        }
+
+        identities
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodePreauthKeyData {
+    pub id: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeUserData {
+    #[serde(default)]
+    pub name: String,
 }
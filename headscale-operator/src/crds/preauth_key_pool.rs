@@ -0,0 +1,86 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition;
+
+use super::*;
+
+use crate::crds::headscale::HeadscaleRef;
+use crate::crds::user::{UserRef, UserStatus};
+
+/// how the keys of a pool are written to secrets
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum PoolSecretLayout {
+    /// a single secret with an `authkey-<slot>` entry per key
+    #[default]
+    Single,
+    /// a secret named `<secret>-<slot>` per key, each with an `authkey` entry
+    PerKey,
+}
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    group = "headscale.juliamertz.dev",
+    version = "v1alpha1",
+    kind = "PreauthKeyPool",
+    status = "PreauthKeyPoolStatus",
+    namespaced,
+    printcolumn = r#"{"name": "Size", "type": "integer", "jsonPath": ".spec.size"}"#,
+    printcolumn = r#"{"name": "Available", "type": "integer", "jsonPath": ".status.available"}"#
+)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PreauthKeyPoolSpec {
+    pub user: UserRef,
+    /// number of unused keys kept available
+    pub size: u32,
+    pub expiration: String,
+    pub ephemeral: bool,
+    pub tags: Vec<String>,
+    pub target_secret: Option<String>,
+    pub secret_layout: PoolSecretLayout,
+}
+
+impl Default for PreauthKeyPoolSpec {
+    fn default() -> Self {
+        Self {
+            user: Default::default(),
+            size: 1,
+            expiration: "24h".to_string(),
+            ephemeral: false,
+            tags: Vec::new(),
+            target_secret: None,
+            secret_layout: Default::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PreauthKeyPoolStatus {
+    /// when the current refill was started, used to find keys leaked by an interrupted refill
+    pub issuing_since: Option<Timestamp>,
+    pub user: Option<UserStatus>,
+    /// headscale instance the keys were issued by, used to expire them after the user is gone
+    pub headscale: Option<HeadscaleRef>,
+    #[serde(default)]
+    pub available: u32,
+    #[serde(default)]
+    pub keys: Vec<PoolKeyStatus>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolKeyStatus {
+    pub slot: u32,
+    pub id: u32,
+    /// sha256 digest of the key, used to detect tampering with the target secret
+    pub key_hash: String,
+    pub expiration: Timestamp,
+    pub created_at: Timestamp,
+    #[serde(default)]
+    pub ephemeral: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// expiration duration from the spec the key was issued with
+    pub requested_expiration: String,
+}
@@ -0,0 +1,204 @@
+//! the `policy explain` subcommand, which shows the rule that decides a connection
+
+use std::path::PathBuf;
+
+use kube::{Api, Client};
+use serde::Deserialize;
+
+use crate::Error;
+use crate::acl::{self, Decision, MergedPolicy};
+use crate::crds::Headscale;
+use crate::crds::policy::{Action, Policy, PolicyConfig};
+use crate::handlers::policy::{Selected, select_acl};
+
+/// reads the policies in the given manifests, other resources are skipped
+fn read_policies(files: &[PathBuf], namespace: &str) -> Result<Vec<Policy>, Error> {
+    let mut policies = Vec::new();
+
+    for file in files {
+        let content = std::fs::read_to_string(file)?;
+        for document in serde_yaml::Deserializer::from_str(&content) {
+            let value = serde_yaml::Value::deserialize(document)?;
+            if value.get("kind").and_then(|kind| kind.as_str()) != Some("Policy") {
+                continue;
+            }
+
+            let mut policy: Policy = serde_yaml::from_value(value)?;
+            if policy.metadata.namespace.is_none() {
+                policy.metadata.namespace = Some(namespace.to_string());
+            }
+            policies.push(policy);
+        }
+    }
+
+    Ok(policies)
+}
+
+/// merges policies read from manifests, raw policies can only be read when they're inline and
+/// selectors aren't expanded as there is no cluster to look them up in
+fn merge_offline(policies: &[Policy]) -> (Vec<Policy>, MergedPolicy) {
+    let mut resolved = Vec::new();
+    let mut unresolved = Vec::new();
+
+    for policy in policies.iter().cloned() {
+        let inline = match policy.spec.raw {
+            Some(ref raw) => raw.inline.clone().ok_or_else(|| acl::FieldError {
+                path: "spec.raw".to_string(),
+                message: "only inline raw policies can be read from a file".to_string(),
+            }),
+            None => {
+                resolved.push(policy);
+                continue;
+            }
+        };
+
+        match inline.and_then(|inline| acl::resolve(&policy.spec, &inline)) {
+            Ok(spec) => resolved.push(Policy { spec, ..policy }),
+            Err(error) => unresolved.push((policy, error)),
+        }
+    }
+
+    let mut merged = acl::build(&resolved, &acl::Target::default());
+    for (policy, error) in &unresolved {
+        merged.reject(policy, vec![error.clone()]);
+    }
+
+    (resolved, merged)
+}
+
+/// short description of the rule behind a decision
+fn describe(config: &PolicyConfig, decision: Decision) -> String {
+    match decision {
+        Decision::Acl(i) => {
+            let acl = &config.acls[i];
+            let action = match acl.action {
+                Action::Accept => "accept",
+                Action::Deny => "deny",
+            };
+            format!("{action} {} -> {}", acl.src.join(", "), acl.dst.join(", "))
+        }
+        Decision::Grant(i) => {
+            let grant = &config.grants.as_deref().unwrap_or_default()[i];
+            format!(
+                "grant {} -> {} on {}",
+                grant.src.join(", "),
+                grant.dst.join(", "),
+                grant.ip.join(", ")
+            )
+        }
+        Decision::Default => "no rule matches, denied by default".to_string(),
+    }
+}
+
+/// prints the policies that are left out of the acl and the tests that fail for it
+fn report(merged: &MergedPolicy) {
+    for invalid in &merged.invalid {
+        let errors: Vec<_> = invalid.errors.iter().map(ToString::to_string).collect();
+        eprintln!(
+            "policy {} is left out of the acl: {}",
+            invalid.policy,
+            errors.join("; ")
+        );
+    }
+
+    for failed in &merged.failed_tests {
+        let failures: Vec<_> = failed.failures.iter().map(ToString::to_string).collect();
+        eprintln!(
+            "tests of policy {} failed: {}",
+            failed.policy,
+            failures.join("; ")
+        );
+    }
+}
+
+/// prints whether `src` may reach `dst` and the rule that decides it, as the acl the operator
+/// writes for the policies in the given manifests, or for the policies of an instance in the
+/// cluster
+pub async fn explain(
+    src: &str,
+    dst: &str,
+    files: &[PathBuf],
+    headscale: Option<&str>,
+    namespace: &str,
+) -> Result<(), Error> {
+    let live = match headscale {
+        Some(name) => {
+            let client = Client::try_default().await?;
+            let api = Api::<Headscale>::namespaced(client.clone(), namespace);
+            Some((api.get(name).await?, client))
+        }
+        None => None,
+    };
+
+    let (policies, resolved, merged) = match &live {
+        Some((headscale, client)) => {
+            let set = headscale.merge_policies(client).await?;
+            (set.policies, set.resolved, set.merged)
+        }
+        None => {
+            let policies = read_policies(files, namespace)?;
+            let (resolved, merged) = merge_offline(&policies);
+            (policies, resolved, merged)
+        }
+    };
+    report(&merged);
+
+    // the acl that is explained is the one the operator writes, or keeps
+    let selected = select_acl(&policies, &merged);
+    let config = match (&selected, &live) {
+        (Selected::Merged, _) => merged.config.clone(),
+        (Selected::Default, Some((headscale, _))) => {
+            eprintln!("no policies reference this instance, explaining its default policy");
+            headscale.default_policy()?
+        }
+        (Selected::Kept(reason), Some((headscale, client))) => {
+            eprintln!("{reason}, explaining the acl headscale keeps");
+            let current = headscale.current_acl(client).await?;
+            current.ok_or_else(|| anyhow::anyhow!("{reason} and no acl was written yet"))?
+        }
+        (Selected::Default, None) => {
+            let message =
+                "no policies in the given manifests, the instance's default policy applies";
+            return Err(anyhow::anyhow!(message).into());
+        }
+        (Selected::Kept(reason), None) => {
+            let message = format!("{reason}, the operator keeps the current acl");
+            return Err(anyhow::anyhow!(message).into());
+        }
+    };
+
+    // ips only match ip and host rules, so they're resolved to the user and tags of their node
+    let mut nodes = acl::NodeIdentities::new();
+    if let Some((headscale, client)) = &live {
+        for node in headscale.list_nodes(client).await? {
+            for address in &node.ip_addresses {
+                nodes.insert(address.clone(), node.identities());
+            }
+        }
+    }
+
+    let explanations =
+        acl::explain(&config, src, dst, &nodes).map_err(|err| anyhow::anyhow!(err))?;
+    for explanation in explanations {
+        let outcome = if explanation.allowed {
+            "allowed"
+        } else {
+            "denied"
+        };
+        let origin = matches!(selected, Selected::Merged)
+            .then(|| merged.origin(&resolved, explanation.decision))
+            .flatten()
+            .map(|(policy, path)| format!(" by {path} of policy {policy}"))
+            .unwrap_or_default();
+
+        println!(
+            "{} -> {}:{} {outcome}{origin}: {}",
+            explanation.src,
+            explanation.dst,
+            explanation.port,
+            describe(&config, explanation.decision)
+        );
+    }
+
+    Ok(())
+}
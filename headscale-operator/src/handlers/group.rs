@@ -0,0 +1,198 @@
+use std::collections::BTreeSet;
+
+use crate::helper::set_condition;
+
+use super::*;
+
+impl Group {
+    /// name of the group in the acl
+    pub fn acl_name(&self) -> String {
+        format!("group:{}", self.name_any())
+    }
+
+    /// headscale users of the members, and the members that aren't users of the same instance,
+    /// users that are being deleted are left out
+    fn resolve_members(&self, users: &[User]) -> (Vec<String>, Vec<String>) {
+        let namespace = self.namespace_any();
+        let mut members = BTreeSet::new();
+        let mut unknown = Vec::new();
+
+        for member in &self.spec.members {
+            let user = users
+                .iter()
+                .filter(|user| user.meta().deletion_timestamp.is_none())
+                .find(|user| member.refers_to(user, &namespace));
+            match user {
+                Some(user) => {
+                    members.insert(format!("{}@", user.name_any()));
+                }
+                None => {
+                    let target = member.namespace.as_deref().unwrap_or(&namespace);
+                    unknown.push(format!("{target}/{}", member.name));
+                }
+            }
+        }
+
+        (members.into_iter().collect(), unknown)
+    }
+
+    /// the acl group this resource defines, keyed by `namespace/name` of the resource
+    pub fn definition(&self, users: &[User]) -> (String, Groups) {
+        let (members, _) = self.resolve_members(users);
+        let key = format!("{}/{}", self.namespace_any(), self.name_any());
+        (key, Groups::from([(self.acl_name(), members)]))
+    }
+}
+
+impl Headscale {
+    /// lists all groups that reference this headscale instance, ordered by namespace and name,
+    /// groups that are being deleted are left out
+    pub async fn list_groups(&self, client: &Client) -> Result<Vec<Group>, Error> {
+        let api = Api::<Group>::all(client.clone());
+        let mut groups: Vec<_> = api
+            .list(&ListParams::default())
+            .await?
+            .items
+            .into_iter()
+            .filter(|group| group.meta().deletion_timestamp.is_none())
+            .filter(|group| {
+                group
+                    .spec
+                    .headscale_ref
+                    .refers_to(self, &group.namespace_any())
+            })
+            .collect();
+
+        groups.sort_by_key(|group| (group.namespace_any(), group.name_any()));
+
+        Ok(groups)
+    }
+}
+
+impl Group {
+    /// resolves the members into the status, and renders the acl again when they changed
+    async fn sync(&self, client: &Client) -> Result<(), Error> {
+        let namespace = self.namespace_any();
+
+        let headscale = self
+            .spec
+            .headscale_ref
+            .resolve(client.clone(), &namespace)
+            .await?;
+        let users = headscale.list_user_resources(client).await?;
+        let (members, unknown) = self.resolve_members(&users);
+
+        // groups are merged in namespace and name order, the first one defines the acl group
+        let groups = headscale.list_groups(client).await?;
+        let defined_by = groups
+            .iter()
+            .find(|other| other.acl_name() == self.acl_name())
+            .filter(|other| other.namespace_any() != namespace);
+
+        let (resolved, reason, message) = match defined_by {
+            Some(other) => (
+                false,
+                "Conflict",
+                format!(
+                    "{} is already defined by group {}/{}",
+                    self.acl_name(),
+                    other.namespace_any(),
+                    other.name_any()
+                ),
+            ),
+            None if !unknown.is_empty() => (
+                false,
+                "UnknownUsers",
+                format!("users not found: {}", unknown.join(", ")),
+            ),
+            None => (true, "Resolved", "all members resolved".to_string()),
+        };
+
+        let current = self.status.clone().unwrap_or_default();
+        let mut status = GroupStatus {
+            members,
+            conditions: current.conditions.clone(),
+        };
+        set_condition(
+            &mut status.conditions,
+            "Resolved",
+            resolved,
+            reason,
+            message,
+            self.meta().generation,
+        );
+        if status == current {
+            return Ok(());
+        }
+
+        let api = Api::<Group>::namespaced(client.clone(), &namespace);
+        api.patch_status(
+            &self.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "status": status })),
+        )
+        .await?;
+
+        // membership changed, render the acl again
+        headscale.sync_policies(client).await?;
+
+        Ok(())
+    }
+}
+
+#[kubus(event = Apply, finalizer = "headscale.juliamertz.dev/group-finalizer")]
+async fn sync_group(group: Arc<Group>, ctx: Arc<Context<State>>) -> Result<(), Error> {
+    group.sync(&ctx.client).await
+}
+
+#[kubus(event = Delete, finalizer = "headscale.juliamertz.dev/group-finalizer")]
+async fn remove_group(group: Arc<Group>, ctx: Arc<Context<State>>) -> Result<(), Error> {
+    let client = &ctx.client;
+    let namespace = group.spec.headscale_ref.namespace.clone();
+    let namespace = namespace.unwrap_or_else(|| group.namespace_any());
+
+    let api = Api::<Headscale>::namespaced(client.clone(), &namespace);
+    let Some(headscale) = api.get_opt(&group.spec.headscale_ref.name).await? else {
+        tracing::debug!(
+            group = group.name_any(),
+            "headscale is gone, nothing to recompute"
+        );
+        return Ok(());
+    };
+
+    // the group is left out while it's being deleted
+    headscale.sync_policies(client).await?;
+
+    Ok(())
+}
+
+/// syncs the groups that have the user as a member, so they pick up its changes right away
+async fn resync_member_groups(client: &Client, user: &User) -> Result<(), Error> {
+    let api = Api::<Group>::all(client.clone());
+    let groups = api.list(&ListParams::default()).await?.items;
+
+    for group in groups {
+        let namespace = group.namespace_any();
+        let member = group
+            .spec
+            .members
+            .iter()
+            .any(|member| member.refers_to(user, &namespace));
+        if member && group.meta().deletion_timestamp.is_none() {
+            group.sync(client).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[kubus(event = Apply, requeue_interval = 3600)]
+async fn resync_user_members(user: Arc<User>, ctx: Arc<Context<State>>) -> Result<(), Error> {
+    resync_member_groups(&ctx.client, &user).await
+}
+
+#[kubus(event = Delete)]
+async fn remove_user_members(user: Arc<User>, ctx: Arc<Context<State>>) -> Result<(), Error> {
+    // the user is left out of its groups while it's being deleted
+    resync_member_groups(&ctx.client, &user).await
+}
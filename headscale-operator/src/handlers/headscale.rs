@@ -1,12 +1,24 @@
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
+
 use serde_json::Value;
+use version_compare::Version;
 
-use crate::helper::{CmdBuilder, Resources};
+use crate::handlers::policy::{ACL_LABEL, POLICY_CHECKED_ANNOTATION, POLICY_HASH_ANNOTATION};
+use crate::helper::{CmdBuilder, ExecError, Resources, sha256_hex};
 use crate::rbac::{ConfigManagerRbac, Rbac};
 
 use super::*;
 
 const ACL_MOUNT_PATH: &str = "/etc/headscale/acls";
 
+/// uid of a headscale pod and the version it runs, a pod runs the same version until it's replaced
+type PodVersion = (String, String);
+
+/// version of the pod of each instance, by namespace and name
+static VERSIONS: LazyLock<Mutex<BTreeMap<(String, String), PodVersion>>> =
+    LazyLock::new(Default::default);
+
 impl HeadscaleRef {
     pub async fn resolve(
         &self,
@@ -22,6 +34,18 @@ impl HeadscaleRef {
 
         api.get(&self.name).await
     }
+
+    /// whether this reference, declared in `namespace`, points at the given headscale instance
+    pub fn refers_to(&self, headscale: &Headscale, namespace: &str) -> bool {
+        let target = self.namespace.as_deref().unwrap_or(namespace);
+        self.name == headscale.name_any() && target == headscale.namespace_any()
+    }
+
+    /// name and namespace of the instance this reference, declared in `namespace`, points at
+    pub fn target(&self, namespace: &str) -> (String, String) {
+        let target = self.namespace.as_deref().unwrap_or(namespace);
+        (self.name.clone(), target.to_string())
+    }
 }
 
 fn default_listen_addr() -> SocketAddr {
@@ -38,6 +62,7 @@ fn default_grpc_listen_addr() -> SocketAddr {
 
 #[derive(Deserialize)]
 struct Config {
+    server_url: Option<String>,
     #[serde(default = "default_listen_addr")]
     listen_addr: SocketAddr,
     #[serde(default = "default_metrics_listen_addr")]
@@ -53,6 +78,15 @@ struct Volumes {
     acls: Volume,
 }
 
+/// where headscale reads its policy from, `policy.mode` of the headscale config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyMode {
+    /// the config-manager writes the acl configmap to a file and signals headscale
+    File,
+    /// the operator sets the policy through the headscale cli
+    Database,
+}
+
 struct Ports {
     http: u16,
     metrics: u16,
@@ -96,6 +130,18 @@ impl Headscale {
         }
     }
 
+    pub fn policy_mode(&self) -> PolicyMode {
+        match self.spec.config["policy"]["mode"].as_str() {
+            Some("database") => PolicyMode::Database,
+            _ => PolicyMode::File,
+        }
+    }
+
+    pub fn server_url(&self) -> Option<String> {
+        let value = self.spec.config.clone();
+        serde_json::from_value::<Config>(value).ok()?.server_url
+    }
+
     fn render_volumes(&self, config: &ConfigMap, keys: &Secret) -> Volumes {
         let keys_name = &keys.name_unchecked();
         let config_name = config.name_unchecked();
@@ -123,6 +169,25 @@ impl Headscale {
         format!("headscale-{}", self.name_unchecked())
     }
 
+    /// whether the deployed statefulset still has the config-manager sidecar of file mode
+    async fn runs_config_manager(&self, client: &Client) -> Result<bool, Error> {
+        let namespace = self.namespace().unwrap_or_default();
+        let api = Api::<StatefulSet>::namespaced(client.clone(), &namespace);
+        let Some(stateful_set) = api.get_opt(&self.stateful_set_name()).await? else {
+            return Ok(false);
+        };
+
+        let containers = stateful_set
+            .spec
+            .and_then(|spec| spec.template.spec)
+            .map(|spec| spec.containers)
+            .unwrap_or_default();
+
+        Ok(containers
+            .iter()
+            .any(|container| container.name == "config-manager"))
+    }
+
     fn render_stateful_set(&self, ports: &Ports, volumes: Volumes) -> StatefulSet {
         let name = self.stateful_set_name();
         let namespace = self.namespace().unwrap_or_default();
@@ -134,49 +199,66 @@ impl Headscale {
             ("MOUNT_PATH", ACL_MOUNT_PATH),
         ];
 
-        let mut pod_spec = PodSpec::containers([
-            Container::new("headscale")
-                .image(&self.spec.deployment.image)
-                .command(["headscale", "serve"])
-                .ports([
-                    ContainerPort::tcp(ports.http).name("http"),
-                    ContainerPort::tcp(ports.metrics).name("metrics"),
-                    ContainerPort::udp(ports.derp).name("derp"),
-                    ContainerPort::tcp(ports.grpc).name("grpc"),
+        let mut headscale_mounts = vec![
+            VolumeMount::new("/etc/headscale/tls", &volumes.tls).read_only(),
+            VolumeMount::new("/var/lib/headscale", &volumes.keys).read_only(),
+            VolumeMount::new("/etc/headscale/config.yaml", &volumes.config)
+                .sub_path("config.yaml")
+                .read_only(),
+        ];
+        if self.policy_mode() == PolicyMode::File {
+            headscale_mounts.push(VolumeMount::new(ACL_MOUNT_PATH, &volumes.acls).read_only());
+        }
+
+        let headscale = Container::new("headscale")
+            .image(&self.spec.deployment.image)
+            .command(["headscale", "serve"])
+            .ports([
+                ContainerPort::tcp(ports.http).name("http"),
+                ContainerPort::tcp(ports.metrics).name("metrics"),
+                ContainerPort::udp(ports.derp).name("derp"),
+                ContainerPort::tcp(ports.grpc).name("grpc"),
+            ])
+            .env(self.spec.deployment.env.clone())
+            .volume_mounts(headscale_mounts);
+
+        let pod_spec = match self.policy_mode() {
+            // the operator sets the policy through headscale, no sidecar is needed
+            PolicyMode::Database => PodSpec::containers([headscale]).volumes([
+                volumes.tls,
+                volumes.keys,
+                volumes.config,
+            ]),
+            PolicyMode::File => {
+                let mut pod_spec = PodSpec::containers([
+                    headscale,
+                    Container::new("config-manager")
+                        .image(&self.spec.config_manager.image)
+                        .command(["/bin/config-manager"])
+                        .env(config_manager_env)
+                        .volume_mounts([VolumeMount::new(ACL_MOUNT_PATH, &volumes.acls)])
+                        .resource_requests(Resources::default().cpu("10m").mem("24Mi").inner())
+                        .resource_limits(Resources::default().cpu("100m").mem("48Mi").inner()),
+                ])
+                .volumes([
+                    volumes.tls,
+                    volumes.keys,
+                    volumes.config,
+                    volumes.acls.clone(),
                 ])
-                .env(self.spec.deployment.env.clone())
-                .volume_mounts([
-                    VolumeMount::new("/etc/headscale/tls", &volumes.tls).read_only(),
-                    VolumeMount::new("/var/lib/headscale", &volumes.keys).read_only(),
-                    VolumeMount::new("/etc/headscale/config.yaml", &volumes.config)
-                        .sub_path("config.yaml")
-                        .read_only(),
-                    VolumeMount::new(ACL_MOUNT_PATH, &volumes.acls).read_only(),
-                ]),
-            Container::new("config-manager")
-                .image(&self.spec.config_manager.image)
-                .command(["/bin/config-manager"])
-                .env(config_manager_env)
-                .volume_mounts([VolumeMount::new(ACL_MOUNT_PATH, &volumes.acls)])
-                .resource_requests(Resources::default().cpu("10m").mem("24Mi").inner())
-                .resource_limits(Resources::default().cpu("100m").mem("48Mi").inner()),
-        ])
-        .volumes([
-            volumes.tls,
-            volumes.keys,
-            volumes.config,
-            volumes.acls.clone(),
-        ])
-        .service_account_name(self.config_manager_service_account_name());
-
-        pod_spec.share_process_namespace = Some(true);
-        pod_spec.init_containers = Some(vec![
-            Container::new("init-config")
-                .image(&self.spec.config_manager.image)
-                .command(["/bin/config-manager", "init"])
-                .env(config_manager_env)
-                .volume_mounts([VolumeMount::new(ACL_MOUNT_PATH, &volumes.acls)]),
-        ]);
+                .service_account_name(self.config_manager_service_account_name());
+
+                pod_spec.share_process_namespace = Some(true);
+                pod_spec.init_containers = Some(vec![
+                    Container::new("init-config")
+                        .image(&self.spec.config_manager.image)
+                        .command(["/bin/config-manager", "init"])
+                        .env(config_manager_env)
+                        .volume_mounts([VolumeMount::new(ACL_MOUNT_PATH, &volumes.acls)]),
+                ]);
+                pod_spec
+            }
+        };
 
         StatefulSet::new(&name)
             .namespace(&namespace)
@@ -239,19 +321,26 @@ impl Headscale {
         format!("headscale-{}-acl", self.name_unchecked())
     }
 
-    fn render_acl_configmap(&self) -> ConfigMap {
+    /// the acl configmap, `checked` tells whether headscale checked the acl before it was written
+    pub fn render_acl_configmap(
+        &self,
+        config: &impl serde::Serialize,
+        checked: bool,
+    ) -> Result<ConfigMap, Error> {
         let name = self.acl_configmap_name();
         let namespace = self.namespace().unwrap_or_default();
         let owner_ref = self.owner_ref(&()).unwrap_or_default();
 
-        ConfigMap::new(&name)
+        let content = serde_json::to_string(config)?;
+
+        Ok(ConfigMap::new(&name)
             .namespace(&namespace)
             .labels(self.common_labels(&name))
+            .label(ACL_LABEL, self.name_any())
+            .annotation(POLICY_HASH_ANNOTATION, sha256_hex(&content))
+            .annotation(POLICY_CHECKED_ANNOTATION, checked.to_string())
             .owner(owner_ref)
-            .data([(
-                "acl.json",
-                serde_json::to_string(&serde_json::json!({})).unwrap(),
-            )])
+            .data([("acl.json", content)]))
     }
 
     pub fn config_manager_service_account_name(&self) -> String {
@@ -298,6 +387,43 @@ impl Headscale {
         I: IntoIterator<Item = T> + Debug + Send + Sync + 'static,
         T: Into<String>,
     {
+        self.exec_with_input(client, command, None).await
+    }
+
+    /// executes a headscale command, writing `input` to its stdin when given
+    pub async fn exec_with_input<I, T>(
+        &self,
+        client: &Client,
+        command: I,
+        input: Option<Vec<u8>>,
+    ) -> Result<String, Error>
+    where
+        I: IntoIterator<Item = T> + Debug + Send + Sync + 'static,
+        T: Into<String>,
+    {
+        self.try_exec_with_input(client, command, input)
+            .await?
+            .map_err(|stderr| anyhow!("error executing command in headscale pod: {stderr}").into())
+    }
+
+    /// executes a headscale command, the outer error is returned when the pod can't be found and
+    /// the inner one when the command failed, so a failing command can be told apart
+    pub async fn try_exec_with_input<I, T>(
+        &self,
+        client: &Client,
+        command: I,
+        input: Option<Vec<u8>>,
+    ) -> Result<Result<String, ExecError>, Error>
+    where
+        I: IntoIterator<Item = T> + Debug + Send + Sync + 'static,
+        T: Into<String>,
+    {
+        let pod = self.pod(client).await?;
+        self.try_exec_in(client, &pod, command, input).await
+    }
+
+    /// the pod headscale commands are executed in
+    async fn pod(&self, client: &Client) -> Result<Pod, Error> {
         let namespace = self.namespace().unwrap_or_default();
         let statefulset_name = self.stateful_set_name();
 
@@ -312,6 +438,23 @@ impl Headscale {
             .cloned()
             .with_context(|| format!("no pods found for {statefulset_name}"))?;
 
+        Ok(pod)
+    }
+
+    /// executes a headscale command in the given pod, errors like [`Self::try_exec_with_input`]
+    async fn try_exec_in<I, T>(
+        &self,
+        client: &Client,
+        pod: &Pod,
+        command: I,
+        input: Option<Vec<u8>>,
+    ) -> Result<Result<String, ExecError>, Error>
+    where
+        I: IntoIterator<Item = T> + Debug + Send + Sync + 'static,
+        T: Into<String>,
+    {
+        let api = Api::<Pod>::namespaced(client.clone(), &pod.namespace_any());
+
         let mut cmd: Vec<_> = ["headscale", "-o", "json-line"]
             .into_iter()
             .map(String::from)
@@ -325,9 +468,15 @@ impl Headscale {
                 .as_slice(),
         );
 
-        api.exec_with_output(&pod.name_unchecked(), cmd)
-            .await
-            .map_err(|stderr| anyhow!("error executing command in headscale pod: {stderr}").into())
+        let output = match input {
+            Some(input) => {
+                api.exec_with_input(&pod.name_unchecked(), cmd, Some(input))
+                    .await
+            }
+            None => api.exec_with_output(&pod.name_unchecked(), cmd).await,
+        };
+
+        Ok(output)
     }
 
     #[allow(dead_code)]
@@ -344,28 +493,61 @@ impl Headscale {
         Ok(output)
     }
 
+    /// version of the running headscale, only asked again once its pod was replaced
     pub async fn get_version(&self, client: &Client) -> Result<String, Error> {
         #[derive(Deserialize)]
         struct Output {
             version: String,
         }
 
-        let stdout = self.exec(client, ["version"]).await?;
+        let pod = self.pod(client).await?;
+        let uid = pod.uid().unwrap_or_default();
+        let key = (self.namespace_any(), self.name_any());
+        if let Some((cached, version)) = VERSIONS.lock().unwrap().get(&key)
+            && *cached == uid
+        {
+            return Ok(version.clone());
+        }
+
+        let stdout = self
+            .try_exec_in(client, &pod, ["version"], None)
+            .await?
+            .map_err(|stderr| anyhow!("error executing command in headscale pod: {stderr}"))?;
         let output: Output = serde_json::from_str(&stdout)?;
 
-        if let Some((version, _)) = output.version.split_once("+") {
-            return Ok(version.to_string());
-        }
+        let version = match output.version.split_once("+") {
+            Some((version, _)) => version.to_string(),
+            None => output
+                .version
+                .strip_prefix("v")
+                .context("invalid version string")?
+                .to_string(),
+        };
+
+        VERSIONS.lock().unwrap().insert(key, (uid, version.clone()));
 
-        Ok(output
-            .version
-            .as_str()
-            .split_once("+")
-            .map(|(version, _)| version)
-            .unwrap_or(&output.version)
-            .strip_prefix("v")
-            .context("invalid version string")?
-            .to_string())
+        Ok(version)
+    }
+
+    /// version in the tag of the headscale image, such as `0.27.1` for `headscale:v0.27.1`
+    pub fn image_version(&self) -> Option<String> {
+        let image = &self.spec.deployment.image;
+        let image = image
+            .split_once('@')
+            .map_or(image.as_str(), |(image, _)| image);
+        let (_, tag) = image.rsplit_once(':')?;
+        let version = tag.strip_prefix('v').unwrap_or(tag);
+
+        version_compare::Version::from(version).map(|_| version.to_string())
+    }
+
+    /// whether the running headscale version is older than `version`
+    pub async fn older_than(&self, client: &Client, version: &str) -> Result<bool, Error> {
+        let current = self.get_version(client).await?;
+        let current = Version::from(&current).context("invalid headscale version output")?;
+        let version = Version::from(version).context("invalid version")?;
+
+        Ok(current < version)
     }
 }
 
@@ -381,7 +563,6 @@ pub async fn deploy_headscale(
     let ports = headscale.get_ports();
     let keys = headscale.render_secret();
     let config = headscale.render_configmap();
-    let acls = headscale.render_acl_configmap();
     let volumes = headscale.render_volumes(&config, &keys);
     let stateful_set = headscale.render_stateful_set(&ports, volumes);
     let service = headscale.render_service(&ports, stateful_set.name_unchecked());
@@ -389,11 +570,27 @@ pub async fn deploy_headscale(
 
     keys.apply_if_not_exists(client).await?;
     config.apply(client).await?;
-    acls.apply_if_not_exists(client).await?;
-    rbac.apply(client, &namespace).await?;
+    match headscale.policy_mode() {
+        PolicyMode::File => rbac.apply(client, &namespace).await?,
+        // left over when switching from file mode, cleaned up before the sidecar is removed so a
+        // failure is retried
+        PolicyMode::Database if headscale.runs_config_manager(client).await? => {
+            rbac.delete(client, &namespace).await?;
+            let acls = ConfigMap::new(headscale.acl_configmap_name()).namespace(&namespace);
+            if acls.clone().exists(client).await? {
+                acls.delete(client).await?;
+            }
+        }
+        PolicyMode::Database => {}
+    }
     stateful_set.apply(client).await?;
     service.apply(client).await?;
 
+    // policies are synced by their own handler as well, a failure here shouldn't block deployment
+    if let Err(err) = headscale.sync_policies(client).await {
+        tracing::warn!({ headscale = &name, error = %err }, "failed to sync policies");
+    }
+
     let api = Api::<Headscale>::namespaced(client.clone(), &namespace);
     api.patch_status(
         &name,
@@ -422,7 +619,7 @@ pub async fn cleanup_headscale(
     let ports = headscale.get_ports();
     let keys = headscale.render_secret();
     let config = headscale.render_configmap();
-    let acls = headscale.render_acl_configmap();
+    let acls = ConfigMap::new(headscale.acl_configmap_name()).namespace(&namespace);
     let volumes = headscale.render_volumes(&config, &keys);
     let stateful_set = headscale.render_stateful_set(&ports, volumes);
     let service = headscale.render_service(&ports, stateful_set.name_unchecked());
@@ -431,7 +628,9 @@ pub async fn cleanup_headscale(
     stateful_set.delete(client).await?;
     service.delete(client).await?;
     config.delete(client).await?;
-    acls.delete(client).await?;
+    if acls.clone().exists(client).await? {
+        acls.delete(client).await?;
+    }
     keys.delete(client).await?;
     rbac.delete(client, &namespace).await?;
 
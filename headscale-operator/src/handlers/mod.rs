@@ -1,6 +1,9 @@
+pub mod group;
 pub mod headscale;
 pub mod policy;
 pub mod preauth_key;
+pub mod preauth_key_pool;
+pub mod sidecar;
 pub mod user;
 
 pub(super) use std::fmt::Debug;
@@ -17,6 +20,8 @@ pub(super) use kubus::{ApiExt, Context, kubus};
 pub(super) use serde::Deserialize;
 pub(super) use serde_json::json;
 
-pub(super) use crate::crds::{headscale::*, policy::*, preauth_key::*, user::*};
+pub(super) use crate::crds::{
+    Timestamp, group::*, headscale::*, policy::*, preauth_key::*, preauth_key_pool::*, user::*,
+};
 pub(super) use crate::helper::{ExecuteExt, ResourceExt as _};
 pub(super) use crate::{Error, State};
@@ -1,38 +1,851 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Node;
+use kube::core::{Selector, SelectorExt as _};
+use kube::runtime::events::EventType;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::acl::{self, MergedPolicy};
+use crate::handlers::headscale::PolicyMode;
+use crate::helper::{
+    CmdBuilder, ExecError, publish_event, set_condition, set_condition_status, sha256_hex,
+};
+
 use super::*;
 
+/// label on the acl configmap, set to the name of the headscale instance
+pub const ACL_LABEL: &str = "headscale.juliamertz.dev/acl";
+/// hash of the acl, set by the operator
+pub const POLICY_HASH_ANNOTATION: &str = "headscale.juliamertz.dev/policy-hash";
+/// whether headscale checked the acl before it was written, set by the operator
+pub const POLICY_CHECKED_ANNOTATION: &str = "headscale.juliamertz.dev/policy-checked";
+/// hash of the acl headscale was last signaled to reload, set by the config-manager
+pub const SYNCED_HASH_ANNOTATION: &str = "headscale.juliamertz.dev/synced-hash";
+/// error of the last reload, set by the config-manager when it couldn't signal headscale
+pub const SYNC_ERROR_ANNOTATION: &str = "headscale.juliamertz.dev/sync-error";
+/// set to `true` on a policy to rewrite its spec in the current policy format
+pub const MIGRATE_ANNOTATION: &str = "headscale.juliamertz.dev/migrate";
+/// held on services and nodes that generate hosts, so their deletion is seen
+pub const HOST_FINALIZER: &str = "headscale.juliamertz.dev/host-finalizer";
+
+/// the policies of an instance and the acl they merge into
+pub struct PolicySet {
+    /// the policies as they are stored
+    pub policies: Vec<Policy>,
+    /// the policies with raw policies parsed and selectors expanded, as they were merged
+    pub resolved: Vec<Policy>,
+    pub merged: MergedPolicy,
+    pub version: Option<String>,
+}
+
+/// where the merged acl was written to
+pub enum Written {
+    /// the acl configmap, headscale reloads it once the config-manager picked it up
+    ConfigMap(Box<ConfigMap>),
+    /// set through the headscale cli, `error` is set when headscale rejected the policy
+    Database { hash: String, error: Option<String> },
+    /// not written to the acl configmap because headscale found errors in it
+    Rejected { hash: String, error: String },
+}
+
+/// how long changes to selected services and nodes are collected before their instances resync
+const HOST_RESYNC_DELAY: Duration = Duration::from_secs(5);
+
+/// a headscale instance by name and namespace
+type Instance = (String, String);
+
+/// hosts a service or node generates, by the instance whose policy selects it
+type GeneratedHosts = BTreeSet<(Instance, String, String)>;
+
+/// the policies with selectors and the hosts generated from services and nodes, so instances only
+/// resync when they change
+#[derive(Debug, Default)]
+pub struct HostResyncs {
+    /// policies with group or host selectors, by namespace and name
+    policies: Mutex<BTreeMap<Instance, Policy>>,
+    /// hosts generated from each object, keyed by its kind, namespace and name
+    generated: Mutex<BTreeMap<String, GeneratedHosts>>,
+    /// instances with a resync scheduled
+    pending: Mutex<BTreeSet<Instance>>,
+}
+
+impl HostResyncs {
+    /// starts out with the policies that have selectors, so objects seen before their policies
+    /// were reconciled still generate hosts
+    pub async fn load(client: &Client) -> Result<Self, Error> {
+        let resyncs = Self::default();
+        let api = Api::<Policy>::all(client.clone());
+        for policy in api.list(&ListParams::default()).await?.items {
+            resyncs.track(&policy);
+        }
+
+        Ok(resyncs)
+    }
+
+    /// keeps the policy while it has selectors, returning whether its host selectors changed
+    fn track(&self, policy: &Policy) -> bool {
+        let key = (policy.name_any(), policy.namespace_any());
+        let selects = policy.spec.group_selectors.is_some() || policy.spec.host_selectors.is_some();
+
+        let mut policies = self.policies.lock().unwrap();
+        let previous = if selects && policy.meta().deletion_timestamp.is_none() {
+            policies.insert(key, policy.clone())
+        } else {
+            policies.remove(&key)
+        };
+
+        let previous = previous.and_then(|previous| previous.spec.host_selectors);
+        previous != policy.spec.host_selectors
+    }
+
+    /// the tracked policies matching `selects`
+    fn selecting(&self, selects: impl Fn(&Policy) -> bool) -> Vec<Policy> {
+        let policies = self.policies.lock().unwrap();
+        policies
+            .values()
+            .filter(|policy| selects(policy))
+            .cloned()
+            .collect()
+    }
+
+    /// records the hosts an object generates, returning the instances whose hosts changed
+    fn update(&self, key: String, hosts: GeneratedHosts) -> BTreeSet<Instance> {
+        let mut generated = self.generated.lock().unwrap();
+        let previous = generated.insert(key, hosts.clone()).unwrap_or_default();
+
+        previous
+            .symmetric_difference(&hosts)
+            .map(|(instance, ..)| instance.clone())
+            .collect()
+    }
+
+    /// forgets the hosts of a deleted object, returning the instances they were generated for
+    fn remove(&self, key: &str, hosts: GeneratedHosts) -> BTreeSet<Instance> {
+        let mut generated = self.generated.lock().unwrap();
+        let previous = generated.remove(key).unwrap_or_default();
+
+        previous
+            .union(&hosts)
+            .map(|(instance, ..)| instance.clone())
+            .collect()
+    }
+
+    /// resyncs the instances after a delay, changes made in the meantime are applied by the same
+    /// resync
+    fn schedule(self: &Arc<Self>, client: &Client, instances: BTreeSet<Instance>) {
+        for instance in instances {
+            if !self.pending.lock().unwrap().insert(instance.clone()) {
+                continue;
+            }
+
+            let resyncs = self.clone();
+            let client = client.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(HOST_RESYNC_DELAY).await;
+                resyncs.pending.lock().unwrap().remove(&instance);
+
+                let (name, namespace) = instance;
+                let api = Api::<Headscale>::namespaced(client.clone(), &namespace);
+                let result = match api.get_opt(&name).await {
+                    Ok(Some(headscale)) => headscale.sync_policies(&client).await.map(drop),
+                    Ok(None) => Ok(()),
+                    Err(err) => Err(err.into()),
+                };
+                if let Err(err) = result {
+                    tracing::warn!(headscale = name, namespace, %err, "failed to resync hosts");
+                }
+            });
+        }
+    }
+}
+
+/// the hosts the host selectors of `policies` generate from an object, `address_of` gives the
+/// address of the object for the selectors that apply to it
+fn generated_hosts(
+    policies: &[Policy],
+    name: &str,
+    labels: &BTreeMap<String, String>,
+    address_of: impl Fn(&Policy, &HostSelector) -> Option<Option<String>>,
+) -> GeneratedHosts {
+    let mut hosts = GeneratedHosts::new();
+
+    for policy in policies {
+        let instance = policy.spec.headscale_ref.target(&policy.namespace_any());
+        for selector in policy.spec.host_selectors.iter().flatten() {
+            let Some(address) = address_of(policy, selector) else {
+                continue;
+            };
+            let Ok(labels_selector) = Selector::try_from(selector.selector.clone()) else {
+                continue;
+            };
+
+            if let Some(address) = address
+                && labels_selector.matches(labels)
+            {
+                let host = format!("{}{name}", selector.prefix);
+                hosts.insert((instance.clone(), host, address));
+            }
+        }
+    }
+
+    hosts
+}
+
+/// the acl written for the policies of an instance
+pub enum Selected {
+    /// the acl the policies merged into
+    Merged,
+    /// the default policy of the instance, as no policy references it
+    Default,
+    /// the current acl is kept, for the given reason
+    Kept(&'static str),
+}
+
+/// the acl written for `policies` and the acl they merged into
+pub fn select_acl(policies: &[Policy], merged: &MergedPolicy) -> Selected {
+    if policies.is_empty() {
+        Selected::Default
+    } else if !merged.failed_tests.is_empty() {
+        Selected::Kept("acl tests failed")
+    } else if merged.invalid.len() == policies.len() {
+        // an empty acl denies everything, keep the current one until a policy is fixed
+        Selected::Kept("no valid policies")
+    } else {
+        Selected::Merged
+    }
+}
+
+/// address of a service, its load balancer ip when it has one
+fn service_address(service: &Service) -> Option<String> {
+    let ingress = service
+        .status
+        .as_ref()
+        .and_then(|status| status.load_balancer.as_ref())
+        .and_then(|load_balancer| load_balancer.ingress.as_ref())
+        .and_then(|ingress| ingress.iter().find_map(|ingress| ingress.ip.clone()));
+
+    ingress.or_else(|| {
+        service
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.cluster_ip.clone())
+            .filter(|ip| !ip.is_empty() && ip != "None")
+    })
+}
+
+/// internal ip or pod cidr of a node
+fn node_address(node: &Node, kind: HostKind) -> Option<String> {
+    match kind {
+        HostKind::NodePodCIDR => node.spec.as_ref().and_then(|spec| spec.pod_cidr.clone()),
+        _ => node
+            .status
+            .as_ref()
+            .and_then(|status| status.addresses.as_ref())
+            .and_then(|addresses| {
+                addresses
+                    .iter()
+                    .find(|address| address.type_ == "InternalIP")
+                    .map(|address| address.address.clone())
+            }),
+    }
+}
+
+/// whether headscale checked the acl before it was written
+enum Check {
+    Passed,
+    Failed(String),
+    /// the running headscale version can't check policies, or its version is unknown
+    Unchecked,
+}
+
+/// the `Synced` condition for a policy written to the acl with the given hash, `None` when it is
+/// unknown whether headscale loaded it
+fn synced_condition(configmap: &ConfigMap, hash: &str) -> (Option<bool>, &'static str, String) {
+    let annotations = kube::ResourceExt::annotations(configmap);
+    let synced = annotations.get(SYNCED_HASH_ANNOTATION);
+    let error = annotations.get(SYNC_ERROR_ANNOTATION);
+    // the config-manager can only signal headscale, a reload succeeds when the acl passed a check
+    let checked = annotations
+        .get(POLICY_CHECKED_ANNOTATION)
+        .map(String::as_str)
+        == Some("true");
+
+    match (synced, error) {
+        (Some(synced), None) if synced == hash && checked => (
+            Some(true),
+            "Reloaded",
+            "headscale reloaded the acl".to_string(),
+        ),
+        (Some(synced), None) if synced == hash => (
+            None,
+            "Unverified",
+            "headscale was signaled to reload the acl, but it couldn't be checked before it was \
+             written, so reload errors only show in the headscale logs"
+                .to_string(),
+        ),
+        (Some(synced), Some(error)) if synced == hash => {
+            (Some(false), "ReloadFailed", error.clone())
+        }
+        _ => (
+            Some(false),
+            "Pending",
+            "waiting for the config-manager to reload headscale".to_string(),
+        ),
+    }
+}
+
 impl Policy {
-    fn common_labels(&self, name: impl ToString) -> impl Iterator<Item = (&'static str, String)> {
-        let name = name.to_string();
-        let manager = env!("CARGO_PKG_NAME").to_string();
-        let version = env!("CARGO_PKG_VERSION").to_string();
-        let instance = format!("headscale-{name}");
-        let part_of = "headscale".to_string();
-        [
-            ("app.kubernetes.io/name", name),
-            ("app.kubernetes.io/managed-by", manager),
-            ("app.kubernetes.io/instance", instance),
-            ("app.kubernetes.io/version", version),
-            ("app.kubernetes.io/part-of", part_of),
-        ]
-        .into_iter()
-    }
-
-    fn render_configmap(&self, name: &str) -> Result<ConfigMap, Error> {
-        let namespace = self.namespace().unwrap();
-        let owner_ref = self.owner_ref(&()).unwrap();
-        let config = PolicyConfig::from(self.spec.clone());
-
-        Ok(ConfigMap::new(name)
-            .namespace(&namespace)
-            .labels(self.common_labels(name))
-            .owner(owner_ref.clone())
-            .data([("acl.json", serde_json::to_string(&config)?)]))
-    }
-}
-
-#[kubus(event = Apply, finalizer = "headscale.juliamertz.dev/acl-policy-finalizer")]
+    async fn patch_status(&self, client: &Client, status: serde_json::Value) -> Result<(), Error> {
+        let api = Api::<Policy>::namespaced(client.clone(), &self.namespace_any());
+        api.patch_status(
+            &self.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "status": status })),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// records whether the policy is part of the merged acl, and the configmap it was written to
+    async fn update_status(
+        &self,
+        client: &Client,
+        merged: &MergedPolicy,
+        written: Option<&Written>,
+    ) -> Result<(), Error> {
+        let current = self.status.clone().unwrap_or_default();
+        let mut status = current.clone();
+        let generation = self.meta().generation;
+
+        let join = |errors: &[acl::FieldError]| -> String {
+            let errors: Vec<_> = errors.iter().map(|error| error.to_string()).collect();
+            errors.join("; ")
+        };
+        let errors = merged.errors_of(self);
+        let failures = merged.failures_of(self);
+
+        let (accepted, reason, message) = if !errors.is_empty() {
+            (false, "InvalidPolicy", join(errors))
+        } else if !failures.is_empty() {
+            (false, "TestsFailed", join(failures))
+        } else if !merged.failed_tests.is_empty() {
+            // failing tests of any policy block the whole acl
+            let failed: Vec<_> = merged
+                .failed_tests
+                .iter()
+                .map(|failed| failed.policy.as_str())
+                .collect();
+            let message = format!("acl not applied, tests of {} failed", failed.join(", "));
+            (false, "TestsFailed", message)
+        } else {
+            (true, "Merged", "policy is part of the acl".to_string())
+        };
+        set_condition(
+            &mut status.conditions,
+            "Accepted",
+            accepted,
+            reason,
+            message,
+            generation,
+        );
+        status.warnings = merged
+            .warnings_of(self)
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect();
+
+        // the policy is part of the acl that was written, headscale may have yet to reload it
+        if let Some(written) = written.filter(|_| accepted) {
+            let (hash, config_map, synced) = match written {
+                Written::ConfigMap(configmap) => {
+                    let annotations = kube::ResourceExt::annotations(&**configmap);
+                    let hash = annotations.get(POLICY_HASH_ANNOTATION).cloned();
+                    let synced = hash
+                        .as_deref()
+                        .map(|hash| synced_condition(configmap, hash));
+                    (hash, Some(configmap.name_any()), synced)
+                }
+                Written::Database { hash, error } => {
+                    let synced = match error {
+                        None => (
+                            Some(true),
+                            "Applied",
+                            "headscale accepted the policy".to_string(),
+                        ),
+                        Some(error) => (Some(false), "SetFailed", error.clone()),
+                    };
+                    (Some(hash.clone()), None, Some(synced))
+                }
+                Written::Rejected { hash, error } => {
+                    let synced = (Some(false), "CheckFailed", error.clone());
+                    (Some(hash.clone()), None, Some(synced))
+                }
+            };
+
+            if let Some((synced, reason, message)) = synced {
+                set_condition_status(
+                    &mut status.conditions,
+                    "Synced",
+                    synced,
+                    reason,
+                    message,
+                    generation,
+                );
+            }
+
+            status.observed_generation = generation;
+            status.hash = hash;
+            status.config_map = config_map;
+        }
+
+        if status == current {
+            return Ok(());
+        }
+
+        self.patch_status(client, serde_json::to_value(&status)?)
+            .await
+    }
+
+    /// rewrites the spec in the current policy format and removes the migrate annotation, raw
+    /// policies are left as they are
+    async fn migrate(&self, client: &Client, hosts: &BTreeSet<String>) -> Result<(), Error> {
+        let mut patch = json!({ "metadata": { "annotations": { MIGRATE_ANNOTATION: null } } });
+        match self.spec.raw {
+            Some(_) => tracing::warn!(
+                policy = self.name_any(),
+                "raw policies can't be migrated, update the hujson instead"
+            ),
+            None => patch["spec"] = serde_json::to_value(acl::migrate(&self.spec, hosts))?,
+        }
+
+        let api = Api::<Policy>::namespaced(client.clone(), &self.namespace_any());
+        api.patch(
+            &self.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(patch),
+        )
+        .await?;
+
+        tracing::info!(policy = self.name_any(), "migrated policy");
+        Ok(())
+    }
+
+    /// updates the `Synced` condition from the acl configmap the policy was last written to
+    async fn sync_status(&self, client: &Client, configmap: &ConfigMap) -> Result<(), Error> {
+        let status = self.status.clone().unwrap_or_default();
+        let Some(ref hash) = status.hash else {
+            return Ok(());
+        };
+
+        let mut conditions = status.conditions.clone();
+        let (synced, reason, message) = synced_condition(configmap, hash);
+        set_condition_status(
+            &mut conditions,
+            "Synced",
+            synced,
+            reason,
+            message,
+            status.observed_generation,
+        );
+        if conditions == status.conditions {
+            return Ok(());
+        }
+
+        self.patch_status(client, json!({ "conditions": conditions }))
+            .await
+    }
+
+    /// the policy with the groups and hosts its selectors generate, explicit hosts take precedence
+    /// over generated ones
+    pub async fn expand_selectors(&self, client: &Client, users: &[User]) -> Result<Policy, Error> {
+        let mut policy = self.clone();
+
+        for (name, selector) in self.spec.group_selectors.iter().flatten() {
+            let selector = Selector::try_from(selector.clone())?;
+            let members: BTreeSet<_> = users
+                .iter()
+                .filter(|user| user.meta().deletion_timestamp.is_none())
+                .filter(|user| selector.matches(kube::ResourceExt::labels(*user)))
+                .map(|user| format!("{}@", user.name_any()))
+                .collect();
+
+            let groups = policy.spec.groups.get_or_insert_default();
+            let group = groups.entry(name.clone()).or_default();
+            for member in members {
+                if !group.contains(&member) {
+                    group.push(member);
+                }
+            }
+        }
+
+        for selector in self.spec.host_selectors.iter().flatten() {
+            let labels = Selector::try_from(selector.selector.clone())?;
+            let params = ListParams::default().labels_from(&labels);
+
+            let addresses: Vec<_> = match selector.kind {
+                HostKind::Service => {
+                    let namespace = selector
+                        .namespace
+                        .clone()
+                        .unwrap_or_else(|| self.namespace_any());
+                    let api = Api::<Service>::namespaced(client.clone(), &namespace);
+                    api.list(&params)
+                        .await?
+                        .items
+                        .iter()
+                        .filter(|service| service.meta().deletion_timestamp.is_none())
+                        .map(|service| (service.name_any(), service_address(service)))
+                        .collect()
+                }
+                kind => {
+                    let api = Api::<Node>::all(client.clone());
+                    api.list(&params)
+                        .await?
+                        .items
+                        .iter()
+                        .filter(|node| node.meta().deletion_timestamp.is_none())
+                        .map(|node| (node.name_any(), node_address(node, kind)))
+                        .collect()
+                }
+            };
+
+            let hosts = policy.spec.hosts.get_or_insert_default();
+            for (name, address) in addresses {
+                if let Some(address) = address {
+                    let name = format!("{}{name}", selector.prefix);
+                    hosts.entry(name).or_insert(address);
+                }
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// the policy with its raw hujson parsed into the regular fields
+    pub async fn resolve_raw(&self, client: &Client) -> Result<Policy, acl::FieldError> {
+        let Some(ref raw) = self.spec.raw else {
+            return Ok(self.clone());
+        };
+
+        let content = match (&raw.inline, &raw.config_map_key_ref) {
+            (Some(inline), None) => inline.clone(),
+            (None, Some(key_ref)) => {
+                let error = |message: String| acl::FieldError {
+                    path: "spec.raw.configMapKeyRef".to_string(),
+                    message,
+                };
+                let api = Api::<ConfigMap>::namespaced(client.clone(), &self.namespace_any());
+                let configmap = api
+                    .get_opt(&key_ref.name)
+                    .await
+                    .map_err(|err| error(err.to_string()))?
+                    .ok_or_else(|| error(format!("configmap '{}' not found", key_ref.name)))?;
+                configmap
+                    .data
+                    .and_then(|mut data| data.remove(&key_ref.key))
+                    .ok_or_else(|| error(format!("key '{}' not found", key_ref.key)))?
+            }
+            _ => {
+                return Err(acl::FieldError {
+                    path: "spec.raw".to_string(),
+                    message: "exactly one of inline or configMapKeyRef must be set".to_string(),
+                });
+            }
+        };
+
+        let mut policy = self.clone();
+        policy.spec = acl::resolve(&self.spec, &content)?;
+        Ok(policy)
+    }
+}
+
+impl Headscale {
+    /// lists all policies that reference this headscale instance, ordered by namespace and name,
+    /// policies that are being deleted are left out
+    pub async fn list_policies(&self, client: &Client) -> Result<Vec<Policy>, Error> {
+        let api = Api::<Policy>::all(client.clone());
+        let mut policies: Vec<_> = api
+            .list(&ListParams::default())
+            .await?
+            .items
+            .into_iter()
+            .filter(|policy| policy.meta().deletion_timestamp.is_none())
+            .filter(|policy| {
+                policy
+                    .spec
+                    .headscale_ref
+                    .refers_to(self, &policy.namespace_any())
+            })
+            .collect();
+
+        policies.sort_by_key(|policy| (policy.namespace_any(), policy.name_any()));
+
+        Ok(policies)
+    }
+
+    /// acl used when no policy references this instance
+    pub fn default_policy(&self) -> Result<PolicyConfig, Error> {
+        let default_policy = &self.spec.default_policy;
+
+        Ok(match default_policy.mode {
+            DefaultPolicyMode::AllowAll => PolicyConfig {
+                acls: vec![Acl {
+                    action: Action::Accept,
+                    src: vec!["*".to_string()],
+                    dst: vec!["*:*".to_string()],
+                    src_posture: None,
+                }],
+                ..Default::default()
+            },
+            DefaultPolicyMode::DenyAll => PolicyConfig::default(),
+            DefaultPolicyMode::Custom => default_policy
+                .custom
+                .clone()
+                .context("defaultPolicy mode custom requires a custom policy")?,
+        })
+    }
+
+    /// the acl headscale was last given, read from the acl configmap or from headscale in
+    /// database mode
+    pub async fn current_acl(&self, client: &Client) -> Result<Option<PolicyConfig>, Error> {
+        let content = match self.policy_mode() {
+            PolicyMode::File => {
+                let api = Api::<ConfigMap>::namespaced(client.clone(), &self.namespace_any());
+                api.get_opt(&self.acl_configmap_name())
+                    .await?
+                    .and_then(|configmap| configmap.data)
+                    .and_then(|mut data| data.remove("acl.json"))
+            }
+            PolicyMode::Database => {
+                let stdout = self.exec(client, ["policy", "get"]).await?;
+                Some(serde_json::from_str::<String>(&stdout)?)
+            }
+        };
+
+        match content {
+            Some(content) => Ok(Some(serde_json::from_str(&content)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// has headscale check the acl, it can only be checked while headscale runs a version with
+    /// `headscale policy check`. errors running the check are returned so it is retried
+    async fn check_policy(&self, client: &Client, content: &str) -> Result<Check, Error> {
+        let Ok(version) = self.get_version(client).await else {
+            return Ok(Check::Unchecked);
+        };
+        if !acl::at_least(&version, acl::POLICY_CHECK_MIN_VERSION) {
+            return Ok(Check::Unchecked);
+        }
+
+        let cmd = CmdBuilder::default()
+            .arg("policy")
+            .arg("check")
+            .option_arg("--file", Some("/dev/stdin"))
+            .collect();
+        let input = Some(content.as_bytes().to_vec());
+
+        match self.try_exec_with_input(client, cmd, input).await? {
+            Ok(_) => Ok(Check::Passed),
+            Err(ExecError::Exit(_, stderr)) => Ok(Check::Failed(stderr.trim().to_string())),
+            Err(err) => Err(anyhow!("failed to check the policy: {err}").into()),
+        }
+    }
+
+    /// writes the acl to the configmap, or sets it through headscale in database mode
+    async fn write_policy(&self, client: &Client, config: &PolicyConfig) -> Result<Written, Error> {
+        let content = serde_json::to_string(config)?;
+        let hash = sha256_hex(&content);
+
+        if self.policy_mode() == PolicyMode::File {
+            // headscale only logs a failed reload, so the acl is checked before it is written
+            let checked = match self.check_policy(client, &content).await? {
+                Check::Passed => true,
+                Check::Unchecked => false,
+                Check::Failed(error) => {
+                    tracing::warn!({ headscale = self.name_any(), error }, "policy check failed");
+                    return Ok(Written::Rejected { hash, error });
+                }
+            };
+
+            let configmap = self.render_acl_configmap(config, checked)?;
+            let configmap = configmap.apply(client).await?;
+            return Ok(Written::ConfigMap(Box::new(configmap)));
+        }
+
+        // setting a policy notifies every node, skip it when nothing changed
+        let current = self
+            .exec(client, ["policy", "get"])
+            .await
+            .ok()
+            .and_then(|stdout| serde_json::from_str::<String>(&stdout).ok())
+            .and_then(|policy| serde_json::from_str::<serde_json::Value>(&policy).ok());
+        if current == Some(serde_json::to_value(config)?) {
+            return Ok(Written::Database { hash, error: None });
+        }
+
+        let cmd = CmdBuilder::default()
+            .arg("policy")
+            .arg("set")
+            .option_arg("--file", Some("/dev/stdin"))
+            .collect();
+        let error = self
+            .exec_with_input(client, cmd, Some(content.into_bytes()))
+            .await
+            .err()
+            .map(|err| err.to_string());
+
+        match error {
+            None => tracing::info!(headscale = self.name_any(), "set policy"),
+            Some(ref error) => {
+                tracing::warn!({ headscale = self.name_any(), error }, "failed to set policy")
+            }
+        }
+
+        Ok(Written::Database { hash, error })
+    }
+
+    /// resolves and merges all policies that reference this instance, without writing the acl
+    pub async fn merge_policies(&self, client: &Client) -> Result<PolicySet, Error> {
+        let policies = self.list_policies(client).await?;
+        let user_resources = self.list_user_resources(client).await?;
+
+        let mut resolved = Vec::with_capacity(policies.len());
+        let mut unresolved = Vec::new();
+        for policy in &policies {
+            match policy.resolve_raw(client).await {
+                Ok(raw) => resolved.push(raw.expand_selectors(client, &user_resources).await?),
+                Err(error) => unresolved.push((policy, error)),
+            }
+        }
+
+        let groups = self
+            .list_groups(client)
+            .await?
+            .iter()
+            .map(|group| group.definition(&user_resources))
+            .collect();
+
+        let users: BTreeSet<_> = user_resources
+            .into_iter()
+            .flat_map(|user| [Some(user.name_any()), user.spec.email])
+            .flatten()
+            .collect();
+
+        // the version decides the policy format, the image tag stands in while headscale is down
+        let version = match self.get_version(client).await {
+            Ok(version) => Some(version),
+            Err(err) => {
+                tracing::debug!(
+                    { headscale = self.name_any(), error = err.to_string() },
+                    "failed to get version, using the image tag"
+                );
+                self.image_version()
+            }
+        };
+
+        let mut merged = acl::build(
+            &resolved,
+            &acl::Target {
+                users: Some(users),
+                version: version.clone(),
+                groups,
+            },
+        );
+        for (policy, error) in unresolved {
+            merged.reject(policy, vec![error]);
+        }
+
+        Ok(PolicySet {
+            policies,
+            resolved,
+            merged,
+            version,
+        })
+    }
+
+    /// merges all policies that reference this instance, writes the result to the acl configmap
+    /// and records the outcome in the status of the policies
+    pub async fn sync_policies(&self, client: &Client) -> Result<MergedPolicy, Error> {
+        let PolicySet {
+            policies,
+            merged,
+            version,
+            ..
+        } = self.merge_policies(client).await?;
+
+        for conflict in &merged.conflicts {
+            tracing::warn!(
+                { headscale = self.name_any(), policy = &conflict.policy },
+                "{conflict}"
+            );
+        }
+
+        for invalid in &merged.invalid {
+            tracing::warn!(
+                { headscale = self.name_any(), policy = &invalid.policy },
+                "policy is invalid and left out of the acl"
+            );
+        }
+
+        for failed in &merged.failed_tests {
+            tracing::warn!(
+                { headscale = self.name_any(), policy = &failed.policy },
+                "acl tests failed"
+            );
+        }
+
+        let config = match select_acl(&policies, &merged) {
+            Selected::Merged => Some(merged.config.clone()),
+            Selected::Default => Some(self.default_policy()?),
+            Selected::Kept(reason) => {
+                tracing::warn!(headscale = self.name_any(), "{reason}, keeping current acl");
+                None
+            }
+        };
+
+        let written = match config {
+            Some(config) => {
+                let config = acl::render(&config, version.as_deref());
+                Some(self.write_policy(client, &config).await?)
+            }
+            None => None,
+        };
+
+        for policy in &policies {
+            policy
+                .update_status(client, &merged, written.as_ref())
+                .await?;
+        }
+
+        match written {
+            Some(Written::Database {
+                error: Some(error), ..
+            }) => return Err(anyhow!("failed to set policy: {error}").into()),
+            Some(Written::Rejected { error, .. }) => {
+                return Err(anyhow!("headscale rejected the policy: {error}").into());
+            }
+            _ => {}
+        }
+
+        Ok(merged)
+    }
+}
+
+#[kubus(
+    event = Apply,
+    finalizer = "headscale.juliamertz.dev/acl-policy-finalizer",
+    requeue_interval = 300
+)]
 async fn create_acl_policy(policy: Arc<Policy>, ctx: Arc<Context<State>>) -> Result<(), Error> {
     let client = ctx.client.clone();
+    if ctx.data.host_resyncs.track(&policy) {
+        hold_selected_hosts(&client, &policy).await?;
+    }
     let namespace = policy.namespace().unwrap_or_default();
 
     let headscale = policy
@@ -41,8 +854,73 @@ async fn create_acl_policy(policy: Arc<Policy>, ctx: Arc<Context<State>>) -> Res
         .resolve(client.clone(), &namespace)
         .await?;
 
-    let name = headscale.acl_configmap_name();
-    policy.render_configmap(&name)?.apply(&client).await?;
+    // the patch triggers another reconcile that syncs the migrated spec, hosts of other policies
+    // are written bare like users and have to be told apart from them
+    let annotations = kube::ResourceExt::annotations(&*policy);
+    if annotations
+        .get(MIGRATE_ANNOTATION)
+        .is_some_and(|value| value == "true")
+    {
+        let set = headscale.merge_policies(&client).await?;
+        let hosts = set.merged.config.hosts.iter().flatten();
+        let hosts = hosts.map(|(name, _)| name.clone()).collect();
+        return policy.migrate(&client, &hosts).await;
+    }
+
+    let merged = headscale.sync_policies(&client).await?;
+
+    let conflicts: Vec<_> = merged
+        .conflicts_of(&policy)
+        .map(|conflict| conflict.to_string())
+        .collect();
+    if !conflicts.is_empty() {
+        let note = format!("ignored conflicting definitions: {}", conflicts.join("; "));
+        publish_event(
+            &client,
+            &*policy,
+            EventType::Warning,
+            "PolicyConflict",
+            note,
+        )
+        .await;
+    }
+
+    let errors: Vec<_> = merged
+        .errors_of(&policy)
+        .iter()
+        .map(|error| error.to_string())
+        .collect();
+    if !errors.is_empty() {
+        let note = format!("policy left out of the acl: {}", errors.join("; "));
+        publish_event(&client, &*policy, EventType::Warning, "InvalidPolicy", note).await;
+    }
+
+    let failures: Vec<_> = merged
+        .failures_of(&policy)
+        .iter()
+        .map(|failure| failure.to_string())
+        .collect();
+    if !failures.is_empty() {
+        let note = format!("acl not applied: {}", failures.join("; "));
+        publish_event(&client, &*policy, EventType::Warning, "TestsFailed", note).await;
+    }
+
+    let warnings: Vec<_> = merged
+        .warnings_of(&policy)
+        .iter()
+        .map(|warning| warning.to_string())
+        .collect();
+    if !warnings.is_empty() {
+        let note = warnings.join("; ");
+        publish_event(
+            &client,
+            &*policy,
+            EventType::Warning,
+            "PolicyWarnings",
+            note,
+        )
+        .await;
+    }
 
     Ok(())
 }
@@ -50,13 +928,244 @@ async fn create_acl_policy(policy: Arc<Policy>, ctx: Arc<Context<State>>) -> Res
 #[kubus(event = Delete, finalizer = "headscale.juliamertz.dev/acl-policy-finalizer")]
 async fn delete_acl_policy(policy: Arc<Policy>, ctx: Arc<Context<State>>) -> Result<(), Error> {
     let client = ctx.client.clone();
-    let name = policy.name_any();
-    let namespace = policy.namespace().unwrap();
+    ctx.data.host_resyncs.track(&policy);
+    let namespace = policy.spec.headscale_ref.namespace.clone();
+    let namespace = namespace.unwrap_or_else(|| policy.namespace_any());
 
-    ConfigMap::new(name)
-        .namespace(namespace)
-        .delete(&client)
-        .await?;
+    let api = Api::<Headscale>::namespaced(client.clone(), &namespace);
+    let Some(headscale) = api.get_opt(&policy.spec.headscale_ref.name).await? else {
+        tracing::debug!(
+            policy = policy.name_any(),
+            "headscale is gone, nothing to recompute"
+        );
+        return Ok(());
+    };
+
+    // the policy is left out while it's being deleted
+    headscale.sync_policies(&client).await?;
+
+    Ok(())
+}
+
+/// reports the reload of the acl by the config-manager on the policies written to it
+#[kubus(event = Apply, label_selector = "headscale.juliamertz.dev/acl")]
+async fn confirm_policy_sync(
+    configmap: Arc<ConfigMap>,
+    ctx: Arc<Context<State>>,
+) -> Result<(), Error> {
+    let client = ctx.client.clone();
+    let labels = kube::ResourceExt::labels(&*configmap);
+    let Some(name) = labels.get(ACL_LABEL) else {
+        return Ok(());
+    };
+
+    let api = Api::<Headscale>::namespaced(client.clone(), &configmap.namespace_any());
+    let Some(headscale) = api.get_opt(name).await? else {
+        return Ok(());
+    };
+
+    for policy in headscale.list_policies(&client).await? {
+        let status = policy.status.as_ref();
+        if status.and_then(|status| status.config_map.as_ref()) == Some(&configmap.name_any()) {
+            policy.sync_status(&client, &configmap).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// resyncs the instances that have a policy matching `selects`, so changes to the objects their
+/// selectors pick are applied right away
+async fn resync_selecting(
+    client: &Client,
+    resyncs: &HostResyncs,
+    selects: impl Fn(&Policy) -> bool,
+) -> Result<(), Error> {
+    let instances: BTreeSet<_> = resyncs
+        .selecting(selects)
+        .iter()
+        .map(|policy| policy.spec.headscale_ref.target(&policy.namespace_any()))
+        .collect();
+
+    for (name, namespace) in instances {
+        let api = Api::<Headscale>::namespaced(client.clone(), &namespace);
+        if let Some(headscale) = api.get_opt(&name).await? {
+            headscale.sync_policies(client).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// holds [`HOST_FINALIZER`] on an object while it generates hosts, and releases it once it doesn't
+async fn hold_while_selected<K>(api: &Api<K>, object: Arc<K>, selected: bool) -> Result<(), Error>
+where
+    K: Resource + Clone + Debug + Serialize + DeserializeOwned,
+{
+    if !selected {
+        kubus::remove_finalizer(api, HOST_FINALIZER, object).await?;
+        return Ok(());
+    }
+    if object
+        .finalizers()
+        .iter()
+        .any(|name| name == HOST_FINALIZER)
+    {
+        return Ok(());
+    }
+
+    // other finalizers are appended to, the resource version guards against lost updates
+    let patch = if object.finalizers().is_empty() {
+        json!([
+            { "op": "test", "path": "/metadata/resourceVersion", "value": object.resource_version() },
+            { "op": "add", "path": "/metadata/finalizers", "value": [HOST_FINALIZER] }
+        ])
+    } else {
+        json!([
+            { "op": "test", "path": "/metadata/resourceVersion", "value": object.resource_version() },
+            { "op": "add", "path": "/metadata/finalizers/-", "value": HOST_FINALIZER }
+        ])
+    };
+    let patch: json_patch::Patch = serde_json::from_value(patch)?;
+    api.patch(
+        &object.name_any(),
+        &PatchParams::default(),
+        &Patch::Json::<()>(patch),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// holds [`HOST_FINALIZER`] on the objects the host selectors of a policy pick, objects it no
+/// longer picks release it on their next reconcile
+async fn hold_selected_hosts(client: &Client, policy: &Policy) -> Result<(), Error> {
+    for selector in policy.spec.host_selectors.iter().flatten() {
+        let labels = Selector::try_from(selector.selector.clone())?;
+        let params = ListParams::default().labels_from(&labels);
+
+        if selector.kind == HostKind::Service {
+            let namespace = selector.namespace.clone();
+            let namespace = namespace.unwrap_or_else(|| policy.namespace_any());
+            let api = Api::<Service>::namespaced(client.clone(), &namespace);
+            for service in api.list(&params).await?.items {
+                if service_address(&service).is_some() {
+                    hold_while_selected(&api, Arc::new(service), true).await?;
+                }
+            }
+        } else {
+            let api = Api::<Node>::all(client.clone());
+            for node in api.list(&params).await?.items {
+                if node_address(&node, selector.kind).is_some() {
+                    hold_while_selected(&api, Arc::new(node), true).await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// the hosts a service generates for the tracked policies
+fn service_hosts(resyncs: &HostResyncs, service: &Service) -> GeneratedHosts {
+    let namespace = service.namespace_any();
+    let policies = resyncs.selecting(|policy| policy.spec.host_selectors.is_some());
+
+    generated_hosts(
+        &policies,
+        &service.name_any(),
+        kube::ResourceExt::labels(service),
+        |policy, selector| {
+            let target = selector.namespace.clone().unwrap_or(policy.namespace_any());
+            let selects = selector.kind == HostKind::Service && target == namespace;
+            selects.then(|| service_address(service))
+        },
+    )
+}
+
+/// the hosts a node generates for the tracked policies
+fn node_hosts(resyncs: &HostResyncs, node: &Node) -> GeneratedHosts {
+    let policies = resyncs.selecting(|policy| policy.spec.host_selectors.is_some());
+
+    generated_hosts(
+        &policies,
+        &node.name_any(),
+        kube::ResourceExt::labels(node),
+        |_, selector| {
+            let selects = selector.kind != HostKind::Service;
+            selects.then(|| node_address(node, selector.kind))
+        },
+    )
+}
+
+#[kubus(event = Apply, requeue_interval = 3600)]
+async fn resync_user_groups(user: Arc<User>, ctx: Arc<Context<State>>) -> Result<(), Error> {
+    let instance = user.spec.headscale_ref.target(&user.namespace_any());
+    resync_selecting(&ctx.client, &ctx.data.host_resyncs, |policy| {
+        policy.spec.group_selectors.is_some()
+            && policy.spec.headscale_ref.target(&policy.namespace_any()) == instance
+    })
+    .await
+}
+
+#[kubus(event = Apply, requeue_interval = 3600)]
+async fn resync_service_hosts(
+    service: Arc<Service>,
+    ctx: Arc<Context<State>>,
+) -> Result<(), Error> {
+    let resyncs = &ctx.data.host_resyncs;
+    let hosts = service_hosts(resyncs, &service);
+    let api = Api::<Service>::namespaced(ctx.client.clone(), &service.namespace_any());
+    hold_while_selected(&api, service.clone(), !hosts.is_empty()).await?;
+
+    let key = format!("service/{}/{}", service.namespace_any(), service.name_any());
+    let changed = resyncs.update(key, hosts);
+    resyncs.schedule(&ctx.client, changed);
+
+    Ok(())
+}
+
+// the finalizer is released by hand, the kubus one doesn't resolve the api of core types
+#[kubus(event = Delete)]
+async fn release_service_hosts(
+    service: Arc<Service>,
+    ctx: Arc<Context<State>>,
+) -> Result<(), Error> {
+    let resyncs = &ctx.data.host_resyncs;
+    let key = format!("service/{}/{}", service.namespace_any(), service.name_any());
+    let removed = resyncs.remove(&key, service_hosts(resyncs, &service));
+    resyncs.schedule(&ctx.client, removed);
+
+    let api = Api::<Service>::namespaced(ctx.client.clone(), &service.namespace_any());
+    kubus::remove_finalizer(&api, HOST_FINALIZER, service).await?;
+
+    Ok(())
+}
+
+#[kubus(event = Apply, requeue_interval = 3600)]
+async fn resync_node_hosts(node: Arc<Node>, ctx: Arc<Context<State>>) -> Result<(), Error> {
+    let resyncs = &ctx.data.host_resyncs;
+    let hosts = node_hosts(resyncs, &node);
+    let api = Api::<Node>::all(ctx.client.clone());
+    hold_while_selected(&api, node.clone(), !hosts.is_empty()).await?;
+
+    let key = format!("node/{}", node.name_any());
+    let changed = resyncs.update(key, hosts);
+    resyncs.schedule(&ctx.client, changed);
+
+    Ok(())
+}
+
+// the finalizer is released by hand, the kubus one doesn't resolve the api of core types
+#[kubus(event = Delete)]
+async fn release_node_hosts(node: Arc<Node>, ctx: Arc<Context<State>>) -> Result<(), Error> {
+    let resyncs = &ctx.data.host_resyncs;
+    let key = format!("node/{}", node.name_any());
+    let removed = resyncs.remove(&key, node_hosts(resyncs, &node));
+    resyncs.schedule(&ctx.client, removed);
+
+    let api = Api::<Node>::all(ctx.client.clone());
+    kubus::remove_finalizer(&api, HOST_FINALIZER, node).await?;
 
     Ok(())
 }
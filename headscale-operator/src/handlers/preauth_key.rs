@@ -1,9 +1,39 @@
-use version_compare::Version;
+use std::collections::BTreeSet;
 
-use crate::helper::CmdBuilder;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition;
+use kube::core::{Selector, SelectorExt as _};
+
+use kube::runtime::events::EventType;
+
+use crate::helper::{CmdBuilder, publish_event, set_condition, sha256_hex};
 
 use super::*;
 
+const REPLICA_LABEL_NAME: &str = "headscale.juliamertz.dev/preauth-key";
+const REPLICA_LABEL_NAMESPACE: &str = "headscale.juliamertz.dev/preauth-key-namespace";
+/// set to `true` on a namespace to accept copies of preauth key secrets from other namespaces
+const REPLICA_OPT_IN_LABEL: &str = "headscale.juliamertz.dev/accept-preauth-keys";
+/// seconds after the start of an issuance in which the keys it created are looked for
+const ISSUANCE_WINDOW: u64 = 60;
+
+/// options for issuing a preauth key, shared by single keys and pools
+pub struct KeyOptions<'a> {
+    pub expiration: &'a str,
+    pub ephemeral: bool,
+    pub reusable: bool,
+    pub tags: &'a [String],
+}
+
+/// what became of the key an interrupted issuance may have left behind
+pub enum Untracked {
+    /// no key was left behind
+    None,
+    /// the key that was left behind was expired
+    Expired(u32),
+    /// several keys match the issuance and can't be told apart from keys created by hand
+    Ambiguous(Vec<u32>),
+}
+
 impl From<UserData> for UserStatus {
     fn from(data: UserData) -> Self {
         UserStatus {
@@ -20,12 +50,257 @@ impl From<UserData> for UserStatus {
 impl From<PreauthKeyData> for PreauthKeyStatus {
     fn from(data: PreauthKeyData) -> Self {
         PreauthKeyStatus {
-            id: data.id,
-            user: data.user.into(),
+            phase: None,
+            issuing_since: None,
+            id: Some(data.id),
+            user: Some(data.user.into()),
+            headscale: None,
             reusable: data.reusable,
             ephemeral: data.ephemeral,
-            expiration: data.expiration,
-            created_at: data.created_at,
+            expiration: Some(data.expiration),
+            created_at: Some(data.created_at),
+            tags: data.acl_tags,
+            requested_expiration: None,
+            key_hash: Some(sha256_hex(&data.key)),
+            used: data.used,
+            expired: false,
+            nodes: Vec::new(),
+            conditions: Vec::new(),
+        }
+    }
+}
+
+impl From<NodeData> for RegisteredNode {
+    fn from(data: NodeData) -> Self {
+        RegisteredNode {
+            id: data.id,
+            hostname: data.name,
+            given_name: data.given_name.filter(|name| !name.is_empty()),
+        }
+    }
+}
+
+impl Headscale {
+    /// lists all preauth keys that belong to the given user
+    pub async fn list_preauth_keys(
+        &self,
+        client: &Client,
+        user_id: u32,
+    ) -> Result<Vec<PreauthKeyData>, Error> {
+        let legacy_cli = self.older_than(client, "0.28.0").await?;
+        let cmd = CmdBuilder::default()
+            .arg("preauthkeys")
+            .arg("list")
+            .option_arg("--user", legacy_cli.then_some(user_id))
+            .collect();
+
+        let stdout = self.exec(client, cmd).await?;
+        let keys: Option<Vec<PreauthKeyData>> = serde_json::from_str(stdout.trim())?;
+
+        Ok(keys
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|key| key.user.id == user_id)
+            .collect())
+    }
+
+    /// lists all nodes registered with headscale
+    pub async fn list_nodes(&self, client: &Client) -> Result<Vec<NodeData>, Error> {
+        let cmd = CmdBuilder::default().arg("nodes").arg("list").collect();
+
+        let stdout = self.exec(client, cmd).await?;
+        let nodes: Option<Vec<NodeData>> = serde_json::from_str(stdout.trim())?;
+
+        Ok(nodes.unwrap_or_default())
+    }
+
+    /// expires a preauth key, older headscale versions can only expire keys by value so the key
+    /// is looked up when it isn't provided
+    pub async fn expire_preauth_key(
+        &self,
+        client: &Client,
+        user_id: u32,
+        id: u32,
+        key: Option<&str>,
+    ) -> Result<(), Error> {
+        let legacy_cli = self.older_than(client, "0.28.0").await?;
+
+        let cmd = if legacy_cli {
+            let key = match key {
+                Some(key) => key.to_string(),
+                None => self
+                    .list_preauth_keys(client, user_id)
+                    .await?
+                    .into_iter()
+                    .find(|key| key.id == id)
+                    .map(|key| key.key)
+                    .with_context(|| format!("preauth key {id} not found"))?,
+            };
+
+            CmdBuilder::default()
+                .arg("preauthkeys")
+                .arg("expire")
+                .arg("--user")
+                .arg(user_id.to_string())
+                .arg(key)
+                .collect()
+        } else {
+            CmdBuilder::default()
+                .arg("preauthkeys")
+                .arg("expire")
+                .arg("--id")
+                .arg(id.to_string())
+                .collect()
+        };
+
+        let _ = self.exec(client, cmd).await?;
+
+        Ok(())
+    }
+
+    /// creates a preauth key for `user`
+    pub async fn create_preauth_key(
+        &self,
+        client: &Client,
+        user: &User,
+        options: &KeyOptions<'_>,
+    ) -> Result<PreauthKeyData, Error> {
+        let user_id = user.id().context("user is missing an id")?;
+
+        let cmd = CmdBuilder::default()
+            .arg("preauthkeys")
+            .arg("create")
+            .option_arg("--user", Some(user_id))
+            .option_arg("--expiration", Some(options.expiration))
+            .bool_arg("--ephemeral", options.ephemeral)
+            .bool_arg("--reusable", options.reusable)
+            .list_arg("--tags", options.tags)
+            .collect();
+
+        self.check_tag_owners(client, user, options.tags).await?;
+
+        let stdout = self.exec(client, cmd).await?;
+        let authkey = serde_json::from_str(stdout.trim())?;
+        Ok(authkey)
+    }
+
+    /// verifies that every requested tag is declared in the `tagOwners` of the policies attached
+    /// to this headscale instance and that the key's user is one of its owners
+    pub async fn check_tag_owners(
+        &self,
+        client: &Client,
+        user: &User,
+        tags: &[String],
+    ) -> Result<(), Error> {
+        if tags.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(tag) = tags.iter().find(|tag| !tag.starts_with("tag:")) {
+            return Err(anyhow!("invalid tag '{tag}', tags must be prefixed with 'tag:'").into());
+        }
+
+        let policies = self.list_policies(client).await?;
+        if policies.is_empty() {
+            tracing::debug!("no policies attached to headscale, skipping tag owner check");
+            return Ok(());
+        }
+
+        let username = user.name_any();
+        let owns = |owner: &str| {
+            if owner.trim_end_matches('@') == username {
+                return true;
+            }
+
+            policies
+                .iter()
+                .filter_map(|policy| policy.spec.groups.as_ref()?.get(owner))
+                .flatten()
+                .any(|member| member.trim_end_matches('@') == username)
+        };
+
+        for tag in tags {
+            let owners: Vec<_> = policies
+                .iter()
+                .filter_map(|policy| policy.spec.tag_owners.as_ref()?.get(tag))
+                .flatten()
+                .collect();
+
+            if owners.is_empty() {
+                return Err(anyhow!(
+                    "tag '{tag}' is not declared in the tagOwners of any policy for headscale {}",
+                    self.name_any()
+                )
+                .into());
+            }
+
+            if !owners.iter().any(|owner| owns(owner)) {
+                return Err(anyhow!("user '{username}' is not an owner of tag '{tag}'").into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// keys of `user` that an issuance started at `since` may have left behind, they match the
+    /// options of the issuance and were created shortly after it started, but no resource tracks
+    /// them
+    async fn untracked_keys(
+        &self,
+        client: &Client,
+        user: &User,
+        since: &Timestamp,
+        options: &KeyOptions<'_>,
+    ) -> Result<Vec<PreauthKeyData>, Error> {
+        let user_id = user.id().context("user is missing an id")?;
+        let owned = owned_key_ids(client, user).await?;
+        let until = since.after(ISSUANCE_WINDOW);
+        let now = Timestamp::now();
+
+        let mut tags = options.tags.to_vec();
+        tags.sort();
+
+        Ok(self
+            .list_preauth_keys(client, user_id)
+            .await?
+            .into_iter()
+            .filter(|key| {
+                let mut key_tags = key.acl_tags.clone();
+                key_tags.sort();
+
+                !owned.contains(&key.id)
+                    && (since.clone()..=until.clone()).contains(&key.created_at)
+                    && key.expiration > now
+                    && key.reusable == options.reusable
+                    && key.ephemeral == options.ephemeral
+                    && key_tags == tags
+            })
+            .collect())
+    }
+
+    /// expires the key an issuance started at `since` created before its id could be recorded.
+    /// an issuance creates at most one key before recording it, so a single match is that key,
+    /// while several matches can't be told apart from keys created by hand and are left alone
+    pub async fn expire_untracked_key(
+        &self,
+        client: &Client,
+        user: &User,
+        since: &Timestamp,
+        options: &KeyOptions<'_>,
+    ) -> Result<Untracked, Error> {
+        let user_id = user.id().context("user is missing an id")?;
+        let keys = self.untracked_keys(client, user, since, options).await?;
+
+        match keys.as_slice() {
+            [] => Ok(Untracked::None),
+            [key] => {
+                self.expire_preauth_key(client, user_id, key.id, Some(&key.key))
+                    .await?;
+                Ok(Untracked::Expired(key.id))
+            }
+            keys => Ok(Untracked::Ambiguous(
+                keys.iter().map(|key| key.id).collect(),
+            )),
         }
     }
 }
@@ -47,19 +322,10 @@ impl PreauthKey {
         .into_iter()
     }
 
-    async fn generate_key(&self, client: Client) -> Result<PreauthKeyData, Error> {
+    /// resolves the user this key is issued for and the headscale instance it belongs to
+    async fn resolve(&self, client: &Client) -> Result<(User, Headscale), Error> {
         let namespace = self.namespace_any();
         let user = self.spec.user.resolve(client.clone(), &namespace).await?;
-        let user_id = user.id().context("user is missing an id")?;
-
-        let cmd = CmdBuilder::default()
-            .arg("preauthkeys")
-            .arg("create")
-            .option_arg("--user", Some(user_id))
-            .option_arg("--expiration", Some(&self.spec.expiration))
-            .bool_arg("--ephemeral", self.spec.ephemeral)
-            .bool_arg("--reusable", self.spec.reusable)
-            .collect();
 
         let headscale = user
             .spec
@@ -67,49 +333,173 @@ impl PreauthKey {
             .resolve(client.clone(), &user.namespace_any())
             .await?;
 
-        let stdout = headscale.exec(&client, cmd).await?;
-        let authkey = serde_json::from_str(stdout.trim())?;
-        Ok(authkey)
+        Ok((user, headscale))
     }
 
-    async fn revoke(&self, client: Client, key: &str) -> Result<(), Error> {
-        let namespace = self.namespace().unwrap();
-        let user = self.spec.user.resolve(client.clone(), &namespace).await?;
-        let user_id = user.id().context("user is missing an id")?;
+    fn key_options(&self) -> KeyOptions<'_> {
+        KeyOptions {
+            expiration: &self.spec.expiration,
+            ephemeral: self.spec.ephemeral,
+            reusable: self.spec.reusable,
+            tags: &self.spec.tags,
+        }
+    }
 
-        let headscale = user
-            .spec
-            .headscale_ref
-            .resolve(client.clone(), &user.namespace_any())
-            .await?;
+    async fn generate_key(
+        &self,
+        client: &Client,
+        user: &User,
+        headscale: &Headscale,
+    ) -> Result<PreauthKeyData, Error> {
+        headscale
+            .create_preauth_key(client, user, &self.key_options())
+            .await
+    }
 
-        let headscale_version = headscale.get_version(&client).await?;
-        let legacy_cli = Version::from(&headscale_version)
-            .context("invalid headscale version output")?
-            < Version::from("0.28.0").expect("valid version");
+    /// expires the key recorded in the given status
+    async fn expire(
+        &self,
+        client: &Client,
+        headscale: &Headscale,
+        status: &PreauthKeyStatus,
+        key: Option<&str>,
+    ) -> Result<(), Error> {
+        let id = status.id.context("cannot expire key without an id")?;
+        let user_id = status
+            .user
+            .as_ref()
+            .map(|user| user.id)
+            .context("cannot expire key without a user")?;
 
-        let cmd = if legacy_cli {
-            CmdBuilder::default()
-                .arg("preauthkeys")
-                .arg("expire")
-                .arg("--user")
-                .arg(user_id.to_string())
-                .arg(key)
-                .collect()
+        headscale.expire_preauth_key(client, user_id, id, key).await
+    }
+
+    /// expires the key recorded in the given status on the instance that issued it, which may not
+    /// be the instance of the user anymore
+    async fn expire_issued(
+        &self,
+        client: &Client,
+        status: &PreauthKeyStatus,
+        key: Option<&str>,
+    ) -> Result<(), Error> {
+        match self.issuer(client, status).await? {
+            Some(issuer) => self.expire(client, &issuer, status, key).await,
+            None => {
+                let note = format!(
+                    "headscale instance no longer exists, skipped expiring preauth key {}",
+                    status.id.unwrap_or_default()
+                );
+                publish_event(client, self, EventType::Warning, "RevocationSkipped", note).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// describes how the spec diverged from the key that was issued, if it did
+    fn drift(&self, status: &PreauthKeyStatus, user: &User) -> Option<&'static str> {
+        let mut tags = self.spec.tags.clone();
+        let mut issued_tags = status.tags.clone();
+        tags.sort();
+        issued_tags.sort();
+
+        let expiration_changed = status
+            .requested_expiration
+            .as_ref()
+            .is_some_and(|expiration| *expiration != self.spec.expiration);
+
+        if user.id() != status.user.as_ref().map(|user| user.id) {
+            Some("user changed")
+        } else if self.spec.reusable != status.reusable {
+            Some("reusable changed")
+        } else if self.spec.ephemeral != status.ephemeral {
+            Some("ephemeral changed")
+        } else if tags != issued_tags {
+            Some("tags changed")
+        } else if expiration_changed {
+            Some("expiration changed")
         } else {
-            let status = self
-                .status
-                .as_ref()
-                .context("cannot expire key without status")?;
-            CmdBuilder::default()
-                .arg("preauthkeys")
-                .arg("expire")
-                .arg("--id")
-                .arg(status.id.to_string())
-                .collect()
+            None
+        }
+    }
+
+    /// refreshes whether the issued key was used or expired and which nodes registered with it,
+    /// publishing an event the first time the key is used or expires
+    async fn refresh_usage(
+        &self,
+        client: &Client,
+        headscale: &Headscale,
+        status: &PreauthKeyStatus,
+    ) -> Result<(), Error> {
+        let (Some(id), Some(user)) = (status.id, status.user.as_ref()) else {
+            return Ok(());
         };
 
-        let _ = headscale.exec(&client, cmd).await?;
+        let key = headscale
+            .list_preauth_keys(client, user.id)
+            .await?
+            .into_iter()
+            .find(|key| key.id == id);
+        let nodes: Vec<RegisteredNode> = headscale
+            .list_nodes(client)
+            .await?
+            .into_iter()
+            .filter(|node| node.pre_auth_key.as_ref().is_some_and(|key| key.id == id))
+            .map(Into::into)
+            .collect();
+
+        // nodes can be deleted, a key stays used once it was
+        let used = status.used || key.as_ref().is_some_and(|key| key.used) || !nodes.is_empty();
+        let expired = key.is_none_or(|key| key.expiration <= Timestamp::now());
+
+        if used && !status.used {
+            let hostnames: Vec<_> = nodes.iter().map(|node| node.hostname.as_str()).collect();
+            let note = match hostnames.is_empty() {
+                true => format!("preauth key {id} was used"),
+                false => format!("preauth key {id} was used by {}", hostnames.join(", ")),
+            };
+            publish_event(client, self, EventType::Normal, "KeyUsed", note).await;
+        }
+
+        if expired && !status.expired {
+            let note = format!("preauth key {id} expired");
+            publish_event(client, self, EventType::Normal, "KeyExpired", note).await;
+        }
+
+        if used != status.used || expired != status.expired || nodes != status.nodes {
+            let patch = json!({ "used": used, "expired": expired, "nodes": nodes });
+            self.patch_status(client, patch).await?;
+        }
+
+        Ok(())
+    }
+
+    /// the conditions of the status with the `Ready` condition set
+    fn ready(&self, status: bool, reason: &str, message: impl Into<String>) -> Vec<Condition> {
+        let mut conditions = self
+            .status
+            .as_ref()
+            .map(|status| status.conditions.clone())
+            .unwrap_or_default();
+        let generation = self.meta().generation;
+        set_condition(
+            &mut conditions,
+            "Ready",
+            status,
+            reason,
+            message,
+            generation,
+        );
+        conditions
+    }
+
+    async fn patch_status(&self, client: &Client, status: serde_json::Value) -> Result<(), Error> {
+        let api = Api::<PreauthKey>::namespaced(client.clone(), &self.namespace_any());
+        api.patch_status(
+            &self.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "status": status })),
+        )
+        .await?;
 
         Ok(())
     }
@@ -122,20 +512,345 @@ impl PreauthKey {
             .unwrap_or_else(|| format!("headscale-preauth-{name}"))
     }
 
-    fn render_secret(&self, preauth_key: impl ToString) -> Secret {
+    /// verifies that the secret template can be rendered for the headscale instance before a key
+    /// is issued for it
+    fn check_secret_template(&self, headscale: &Headscale) -> Result<(), Error> {
+        let Some(ref template) = self.spec.secret_template else {
+            return Ok(());
+        };
+
+        let needs_server_url = template.login_server
+            || template.extra_args.is_some()
+            || template.tailscaled_config.is_some();
+
+        if needs_server_url && headscale.server_url().is_none() {
+            return Err(anyhow!(
+                "secret template requires the server_url of headscale {}",
+                headscale.name_any()
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn render_secret(&self, preauth_key: &str, headscale: &Headscale) -> Result<Secret, Error> {
         let namespace = self.namespace().unwrap_or_default();
         let owner_ref = self.owner_ref(&()).unwrap_or_default();
         let secret_name = self.secret_name();
+        let template = self.spec.secret_template.clone().unwrap_or_default();
+
+        let server_url = || {
+            headscale
+                .server_url()
+                .context("headscale config is missing a server_url")
+        };
+
+        let mut data = vec![("authkey".to_string(), preauth_key.to_string())];
+
+        if template.login_server {
+            data.push(("loginServer".to_string(), server_url()?));
+        }
 
-        Secret::new(&secret_name)
+        if let Some(extra_args) = template.extra_args {
+            let login_server = format!("--login-server={}", server_url()?);
+            let args = format!("{login_server} {extra_args}").trim().to_string();
+            data.push(("TS_EXTRA_ARGS".to_string(), args));
+        }
+
+        if let Some(template) = template.tailscaled_config {
+            let config = TailscaledConfig {
+                version: "alpha0",
+                server_url: server_url()?,
+                auth_key: preauth_key.to_string(),
+                hostname: template.hostname,
+                accept_dns: template.accept_dns,
+                accept_routes: template.accept_routes,
+                advertise_routes: (!template.advertise_routes.is_empty())
+                    .then_some(template.advertise_routes),
+                run_ssh_server: template.run_ssh_server,
+            };
+            data.push((template.key, serde_json::to_string(&config)?));
+        }
+
+        Ok(Secret::new(&secret_name)
             .namespace(&namespace)
             .labels(self.common_labels(&secret_name))
+            .labels(template.labels)
+            .annotations(template.annotations)
             .owner(owner_ref)
-            .string_data([("authkey", preauth_key)])
+            .string_data(data))
+    }
+
+    /// headscale instance the key was issued by, `None` when it no longer exists
+    async fn issuer(
+        &self,
+        client: &Client,
+        status: &PreauthKeyStatus,
+    ) -> Result<Option<Headscale>, Error> {
+        let (headscale_ref, namespace) = match status.headscale {
+            Some(ref headscale_ref) => (headscale_ref.clone(), self.namespace_any()),
+            // statuses written before the instance was recorded need the user to find it
+            None => {
+                let namespace = self
+                    .spec
+                    .user
+                    .namespace
+                    .clone()
+                    .unwrap_or_else(|| self.namespace_any());
+                let user = Api::<User>::namespaced(client.clone(), &namespace)
+                    .get_opt(&self.spec.user.name)
+                    .await?
+                    .with_context(|| format!("user {} no longer exists", self.spec.user.name))?;
+                (user.spec.headscale_ref.clone(), user.namespace_any())
+            }
+        };
+
+        let namespace = headscale_ref.namespace.unwrap_or(namespace);
+        let api = Api::<Headscale>::namespaced(client.clone(), &namespace);
+
+        Ok(api.get_opt(&headscale_ref.name).await?)
+    }
+
+    /// expires the issued key and removes its secrets, tolerating a missing secret, user or
+    /// headscale instance
+    async fn revoke(&self, client: &Client) -> Result<(), Error> {
+        let namespace = self.namespace_any();
+        let secret_name = self.secret_name();
+        let status = self.status.clone().unwrap_or_default();
+        let api = Api::<Secret>::namespaced(client.clone(), &namespace);
+
+        if let (Some(id), Some(user)) = (status.id, status.user.as_ref()) {
+            match self.issuer(client, &status).await? {
+                Some(headscale) => {
+                    let issued = headscale
+                        .list_preauth_keys(client, user.id)
+                        .await?
+                        .into_iter()
+                        .find(|key| key.id == id);
+
+                    // keys are expired by id when the secret is gone or was modified
+                    let authkey = api
+                        .get_opt(&secret_name)
+                        .await?
+                        .and_then(|secret| read_secret_entry(&secret, "authkey"))
+                        .filter(|key| {
+                            status
+                                .key_hash
+                                .as_ref()
+                                .is_none_or(|hash| *hash == sha256_hex(key))
+                        });
+
+                    match issued {
+                        Some(key) if key.expiration > Timestamp::now() => {
+                            self.expire(client, &headscale, &status, authkey.as_deref())
+                                .await?;
+                        }
+                        _ => tracing::debug!(
+                            { preauth_key = self.name_any(), id },
+                            "preauth key already expired or removed"
+                        ),
+                    }
+                }
+                None => {
+                    let note = format!(
+                        "headscale instance no longer exists, skipped expiring preauth key {id}"
+                    );
+                    publish_event(client, self, EventType::Warning, "RevocationSkipped", note)
+                        .await;
+                }
+            }
+        }
+
+        if api.get_opt(&secret_name).await?.is_some() {
+            api.delete(&secret_name, &Default::default()).await?;
+        }
+
+        for replica in self.list_replicas(client).await? {
+            replica.delete(client).await?;
+        }
+
+        Ok(())
+    }
+
+    /// namespaces the secret is replicated to, never includes the namespace of the resource. only
+    /// namespaces that opted in with the replica label receive copies
+    async fn replica_namespaces(&self, client: &Client) -> Result<BTreeSet<String>, Error> {
+        let params = ListParams::default().labels(&format!("{REPLICA_OPT_IN_LABEL}=true"));
+        let accepting = Api::<Namespace>::all(client.clone()).list(&params).await?;
+
+        let mut namespaces = BTreeSet::new();
+        for target in &self.spec.target_namespaces {
+            match accepting.items.iter().any(|ns| ns.name_any() == *target) {
+                true => {
+                    namespaces.insert(target.clone());
+                }
+                false => {
+                    let note = format!(
+                        "namespace {target} doesn't accept preauth key secrets, label it with \
+                         {REPLICA_OPT_IN_LABEL}=true"
+                    );
+                    publish_event(client, self, EventType::Warning, "ReplicaRefused", note).await;
+                }
+            }
+        }
+
+        if let Some(ref selector) = self.spec.namespace_selector {
+            let selector = Selector::try_from(selector.clone())?;
+            namespaces.extend(
+                accepting
+                    .items
+                    .iter()
+                    .filter(|ns| selector.matches(kube::ResourceExt::labels(*ns)))
+                    .map(|ns| ns.name_any()),
+            );
+        }
+
+        namespaces.remove(&self.namespace_any());
+
+        Ok(namespaces)
+    }
+
+    /// whether a secret is a replica of this resource
+    fn is_replica(&self, secret: &Secret) -> bool {
+        let labels = kube::ResourceExt::labels(secret);
+        labels.get(REPLICA_LABEL_NAME) == Some(&self.name_any())
+            && labels.get(REPLICA_LABEL_NAMESPACE) == Some(&self.namespace_any())
+    }
+
+    async fn list_replicas(&self, client: &Client) -> Result<Vec<Secret>, Error> {
+        let labels = format!(
+            "{REPLICA_LABEL_NAME}={},{REPLICA_LABEL_NAMESPACE}={}",
+            self.name_any(),
+            self.namespace_any()
+        );
+        let api = Api::<Secret>::all(client.clone());
+        let secrets = api.list(&ListParams::default().labels(&labels)).await?;
+
+        Ok(secrets.items)
     }
+
+    /// replicas can't be owned by the resource across namespaces, they are tracked by label instead
+    fn render_replica(&self, secret: &Secret, namespace: &str) -> Secret {
+        let mut replica = secret.clone();
+        replica.metadata.namespace = Some(namespace.to_string());
+        replica.metadata.owner_references = None;
+
+        replica.labels([
+            (REPLICA_LABEL_NAME, self.name_any()),
+            (REPLICA_LABEL_NAMESPACE, self.namespace_any()),
+        ])
+    }
+
+    /// copies the secret to all target namespaces and removes replicas from namespaces that are no
+    /// longer targeted
+    async fn sync_replicas(&self, client: &Client, secret: &Secret) -> Result<(), Error> {
+        let namespaces = self.replica_namespaces(client).await?;
+
+        for namespace in &namespaces {
+            // secrets that aren't replicas of this resource are never taken over
+            let api = Api::<Secret>::namespaced(client.clone(), namespace);
+            let existing = api.get_opt(&secret.name_any()).await?;
+            if let Some(existing) = existing
+                && !self.is_replica(&existing)
+            {
+                let note = format!(
+                    "secret {namespace}/{} already exists and isn't a copy of this preauth key",
+                    existing.name_any()
+                );
+                publish_event(client, self, EventType::Warning, "ReplicaRefused", note).await;
+                continue;
+            }
+
+            self.render_replica(secret, namespace).apply(client).await?;
+        }
+
+        for replica in self.list_replicas(client).await? {
+            if !namespaces.contains(&replica.namespace_any()) {
+                tracing::info!(
+                    { preauth_key = self.name_any(), namespace = replica.namespace_any() },
+                    "removing preauth key secret replica"
+                );
+                replica.delete(client).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub(super) fn read_secret_entry(secret: &Secret, key: &str) -> Option<String> {
+    let value = secret.data.as_ref()?.get(key)?;
+    String::from_utf8(value.0.clone()).ok()
 }
 
-#[kubus(event = Apply, finalizer = "headscale.juliamertz.dev/preauth-key-finalizer")]
+/// reports the key an interrupted issuance left behind, returning the message of the failing
+/// `Ready` condition when no key may be issued until the keys are expired by hand
+pub(super) async fn report_untracked<K>(
+    client: &Client,
+    resource: &K,
+    untracked: Untracked,
+) -> Option<String>
+where
+    K: Resource<DynamicType = ()>,
+{
+    match untracked {
+        Untracked::None => None,
+        Untracked::Expired(id) => {
+            let note = format!("expired preauth key {id} left behind by an interrupted issuance");
+            publish_event(
+                client,
+                resource,
+                EventType::Normal,
+                "UntrackedKeyExpired",
+                note,
+            )
+            .await;
+            None
+        }
+        Untracked::Ambiguous(ids) => {
+            let ids: Vec<_> = ids.iter().map(u32::to_string).collect();
+            let message = format!(
+                "an interrupted issuance left untracked preauth keys behind that can't be told \
+                 apart, expire the ones that aren't used by hand: {}",
+                ids.join(", ")
+            );
+            let note = message.clone();
+            publish_event(client, resource, EventType::Warning, "UntrackedKeys", note).await;
+            Some(message)
+        }
+    }
+}
+
+/// ids of all keys of `user` that are tracked by a PreauthKey or PreauthKeyPool resource
+async fn owned_key_ids(client: &Client, user: &User) -> Result<BTreeSet<u32>, Error> {
+    let keys = Api::<PreauthKey>::all(client.clone())
+        .list(&ListParams::default())
+        .await?;
+    let pools = Api::<PreauthKeyPool>::all(client.clone())
+        .list(&ListParams::default())
+        .await?;
+
+    let key_ids = keys
+        .items
+        .iter()
+        .filter(|key| key.spec.user.refers_to(user, &key.namespace_any()))
+        .filter_map(|key| key.status.as_ref()?.id);
+    let pool_ids = pools
+        .items
+        .iter()
+        .filter(|pool| pool.spec.user.refers_to(user, &pool.namespace_any()))
+        .filter_map(|pool| pool.status.as_ref())
+        .flat_map(|status| status.keys.iter().map(|key| key.id));
+
+    Ok(key_ids.chain(pool_ids).collect())
+}
+
+#[kubus(
+    event = Apply,
+    finalizer = "headscale.juliamertz.dev/preauth-key-finalizer",
+    requeue_interval = 60
+)]
 async fn create_preauth_key(
     resource: Arc<PreauthKey>,
     ctx: Arc<Context<State>>,
@@ -146,28 +861,134 @@ async fn create_preauth_key(
     let namespace = resource.namespace_any();
     let secret_name = resource.secret_name();
 
-    let exists = Secret::new(&secret_name)
-        .namespace(&namespace)
-        .exists(&client)
-        .await?;
+    let (user, headscale) = resource.resolve(&client).await?;
+    let authkey = Api::<Secret>::namespaced(client.clone(), &namespace)
+        .get_opt(&secret_name)
+        .await?
+        .as_ref()
+        .and_then(|secret| read_secret_entry(secret, "authkey"));
 
-    if !exists {
-        let data = resource.generate_key(client.clone()).await?;
+    let status = resource.status.clone().unwrap_or_default();
+    let stored = |key: &str| {
+        status
+            .key_hash
+            .as_ref()
+            .is_none_or(|hash| *hash == sha256_hex(key))
+    };
 
-        let secret = resource.render_secret(&data.key);
-        secret.apply(&client).await?;
+    match status.phase() {
+        PreauthKeyPhase::Issued => {
+            let reason = match authkey.as_deref() {
+                None => Some("target secret is missing"),
+                Some(key) if !stored(key) => Some("target secret was modified"),
+                Some(_) => resource.drift(&status, &user),
+            };
 
-        let status: PreauthKeyStatus = data.into();
-        let api = Api::<PreauthKey>::namespaced(client.clone(), &namespace);
-        api.patch_status(
-            &name,
-            &PatchParams::default(),
-            &Patch::Merge(json!({ "status": status })),
-        )
-        .await?;
-        tracing::info!("succesfully patched status");
+            let Some(reason) = reason else {
+                // keys issued before these fields existed are adopted as-is
+                let incomplete = status.key_hash.is_none()
+                    || status.requested_expiration.is_none()
+                    || status.headscale.is_none();
+                if incomplete {
+                    let patch = json!({
+                        "keyHash": authkey.as_deref().map(sha256_hex),
+                        "requestedExpiration": resource.spec.expiration,
+                        "headscale": {
+                            "name": headscale.name_any(),
+                            "namespace": headscale.namespace(),
+                        },
+                    });
+                    resource.patch_status(&client, patch).await?;
+                }
+
+                // keep the secret and its replicas in line with the spec
+                if let Some(key) = authkey.as_deref() {
+                    let secret = resource.render_secret(key, &headscale)?;
+                    secret.clone().apply(&client).await?;
+                    resource.sync_replicas(&client, &secret).await?;
+                }
+
+                return resource.refresh_usage(&client, &headscale, &status).await;
+            };
+
+            tracing::info!({ preauth_key = &name, reason }, "regenerating preauth key");
+            // the secret is only trusted when it still holds the issued key
+            let authkey = authkey.as_deref().filter(|key| stored(key));
+            resource.expire_issued(&client, &status, authkey).await?;
+        }
+
+        PreauthKeyPhase::Issuing => match status.id {
+            Some(_) if status.key_hash.is_some() && authkey.as_deref().is_some_and(stored) => {
+                tracing::info!({ preauth_key = &name }, "completing interrupted issuance");
+                let patch = json!({ "phase": PreauthKeyPhase::Issued, "issuingSince": null });
+                return resource.patch_status(&client, patch).await;
+            }
+            Some(_) => {
+                tracing::info!(
+                    { preauth_key = &name },
+                    "expiring key of interrupted issuance, it was never stored"
+                );
+                resource.expire_issued(&client, &status, None).await?;
+            }
+            // the key may have been created before its id could be recorded
+            None => {
+                if let Some(ref since) = status.issuing_since {
+                    let untracked = headscale
+                        .expire_untracked_key(&client, &user, since, &resource.key_options())
+                        .await?;
+                    if let Some(message) = report_untracked(&client, &*resource, untracked).await {
+                        // stays in issuing, a second valid key must not be created
+                        let conditions = resource.ready(false, "UntrackedKeys", message);
+                        return resource
+                            .patch_status(&client, json!({ "conditions": conditions }))
+                            .await;
+                    }
+                }
+            }
+        },
+
+        PreauthKeyPhase::Pending => {}
     }
 
+    resource.check_secret_template(&headscale)?;
+
+    // record the intent to issue a key before anything is created in headscale
+    let issuing_since = Timestamp::now();
+    let patch = json!({
+        "phase": PreauthKeyPhase::Issuing,
+        "issuingSince": issuing_since,
+        "id": null,
+        "keyHash": null,
+    });
+    resource.patch_status(&client, patch).await?;
+
+    // record the id right away, so a retry can expire the key if the secret is never written
+    let data = resource.generate_key(&client, &user, &headscale).await?;
+    let key = data.key.clone();
+    let status = PreauthKeyStatus {
+        phase: Some(PreauthKeyPhase::Issuing),
+        issuing_since: Some(issuing_since),
+        requested_expiration: Some(resource.spec.expiration.clone()),
+        headscale: Some(HeadscaleRef {
+            name: headscale.name_any(),
+            namespace: headscale.namespace(),
+        }),
+        ..data.into()
+    };
+    resource.patch_status(&client, json!(status)).await?;
+
+    let secret = resource.render_secret(&key, &headscale)?;
+    secret.clone().apply(&client).await?;
+    resource.sync_replicas(&client, &secret).await?;
+
+    let patch = json!({
+        "phase": PreauthKeyPhase::Issued,
+        "issuingSince": null,
+        "conditions": resource.ready(true, "Issued", "preauth key was issued"),
+    });
+    resource.patch_status(&client, patch).await?;
+    tracing::info!({ preauth_key = &name }, "preauth key issued");
+
     Ok(())
 }
 
@@ -178,31 +999,38 @@ async fn revoke_preauth_key(
 ) -> Result<(), Error> {
     let client = ctx.client.clone();
 
-    let namespace = resource.namespace().unwrap_or_default();
-    let secret_name = resource.secret_name();
-
-    let api = Api::<Secret>::namespaced(client.clone(), &namespace);
-    let secret = api.get(&secret_name).await?;
+    let Err(err) = resource.revoke(&client).await else {
+        return Ok(());
+    };
 
-    let data = secret
-        .data
-        .clone()
-        .context("expected preauth key secret data")?;
+    let deleting_for = resource
+        .meta()
+        .deletion_timestamp
+        .as_ref()
+        .and_then(|time| (k8s_openapi::chrono::Utc::now() - time.0).to_std().ok())
+        .unwrap_or_default();
 
-    let preauth_key = data
-        .get("authkey")
-        .context("unable to get preauth key secret value")
-        .map(|v| v.0.clone())
-        .map(String::from_utf8)
-        .unwrap()
-        .unwrap();
-
-    resource.revoke(client.clone(), &preauth_key).await?;
+    if deleting_for < ctx.data.finalizer_timeout {
+        return Err(err);
+    }
 
-    Secret::new(secret_name)
-        .namespace(namespace)
-        .delete(&client)
-        .await?;
+    // the resource would otherwise be stuck in deletion forever
+    tracing::warn!(
+        { preauth_key = resource.name_any(), error = %err },
+        "finalizer timeout reached, releasing preauth key without revoking it"
+    );
+    let note = format!(
+        "gave up revoking the key after {}s, it may still be valid: {err}",
+        ctx.data.finalizer_timeout.as_secs()
+    );
+    publish_event(
+        &client,
+        &*resource,
+        EventType::Warning,
+        "RevocationFailed",
+        note,
+    )
+    .await;
 
     Ok(())
 }
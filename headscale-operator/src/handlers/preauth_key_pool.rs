@@ -0,0 +1,489 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::ByteString;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition;
+use kube::runtime::events::EventType;
+
+use crate::handlers::preauth_key::{KeyOptions, read_secret_entry, report_untracked};
+use crate::helper::{publish_event, set_condition, sha256_hex};
+
+use super::*;
+
+const POOL_LABEL: &str = "headscale.juliamertz.dev/preauth-key-pool";
+const SLOT_LABEL: &str = "headscale.juliamertz.dev/preauth-key-pool-slot";
+const SLOT_ENTRY_PREFIX: &str = "authkey-";
+
+impl PreauthKeyPool {
+    fn common_labels(&self, name: impl ToString) -> impl Iterator<Item = (&'static str, String)> {
+        let name = name.to_string();
+        let manager = env!("CARGO_PKG_NAME").to_string();
+        let version = env!("CARGO_PKG_VERSION").to_string();
+        let instance = format!("headscale-{name}");
+        let part_of = "headscale".to_string();
+        [
+            ("app.kubernetes.io/name", name),
+            ("app.kubernetes.io/managed-by", manager),
+            ("app.kubernetes.io/instance", instance),
+            ("app.kubernetes.io/version", version),
+            ("app.kubernetes.io/part-of", part_of),
+            (POOL_LABEL, self.name_any()),
+        ]
+        .into_iter()
+    }
+
+    /// resolves the user the keys are issued for and the headscale instance it belongs to
+    async fn resolve(&self, client: &Client) -> Result<(User, Headscale), Error> {
+        let namespace = self.namespace_any();
+        let user = self.spec.user.resolve(client.clone(), &namespace).await?;
+
+        let headscale = user
+            .spec
+            .headscale_ref
+            .resolve(client.clone(), &user.namespace_any())
+            .await?;
+
+        Ok((user, headscale))
+    }
+
+    /// headscale instance the keys were issued by, `None` when it or the user it was looked up
+    /// through no longer exists
+    async fn issuer(
+        &self,
+        client: &Client,
+        status: &PreauthKeyPoolStatus,
+    ) -> Result<Option<Headscale>, Error> {
+        let (headscale_ref, namespace) = match status.headscale {
+            Some(ref headscale_ref) => (headscale_ref.clone(), self.namespace_any()),
+            // statuses written before the instance was recorded need the user to find it
+            None => {
+                let namespace = self
+                    .spec
+                    .user
+                    .namespace
+                    .clone()
+                    .unwrap_or_else(|| self.namespace_any());
+                let user = Api::<User>::namespaced(client.clone(), &namespace)
+                    .get_opt(&self.spec.user.name)
+                    .await?;
+                let Some(user) = user else {
+                    return Ok(None);
+                };
+                (user.spec.headscale_ref.clone(), user.namespace_any())
+            }
+        };
+
+        let namespace = headscale_ref.namespace.unwrap_or(namespace);
+        let api = Api::<Headscale>::namespaced(client.clone(), &namespace);
+
+        Ok(api.get_opt(&headscale_ref.name).await?)
+    }
+
+    fn secret_name(&self) -> String {
+        let name = self.name_unchecked();
+        self.spec
+            .target_secret
+            .clone()
+            .unwrap_or_else(|| format!("headscale-preauth-pool-{name}"))
+    }
+
+    /// names of the secrets the pool writes its keys to, for every slot of the pool
+    fn secret_names(&self) -> Vec<String> {
+        match self.spec.secret_layout {
+            PoolSecretLayout::Single => vec![self.secret_name()],
+            PoolSecretLayout::PerKey => (0..self.spec.size)
+                .map(|slot| format!("{}-{slot}", self.secret_name()))
+                .collect(),
+        }
+    }
+
+    /// secrets the pool would write to that exist but aren't owned by it, they're never taken over
+    async fn foreign_secrets(&self, client: &Client) -> Result<Vec<String>, Error> {
+        let api = Api::<Secret>::namespaced(client.clone(), &self.namespace_any());
+        let uid = self.uid();
+
+        let mut foreign = Vec::new();
+        for name in self.secret_names() {
+            let Some(secret) = api.get_opt(&name).await? else {
+                continue;
+            };
+            let owned = secret
+                .owner_references()
+                .iter()
+                .any(|owner| Some(&owner.uid) == uid.as_ref());
+            if !owned {
+                foreign.push(name);
+            }
+        }
+
+        Ok(foreign)
+    }
+
+    async fn list_secrets(&self, client: &Client) -> Result<Vec<Secret>, Error> {
+        let api = Api::<Secret>::namespaced(client.clone(), &self.namespace_any());
+        let labels = format!("{POOL_LABEL}={}", self.name_any());
+        let secrets = api.list(&ListParams::default().labels(&labels)).await?;
+
+        Ok(secrets.items)
+    }
+
+    /// keys currently stored in the secrets of the pool, by slot
+    async fn read_keys(&self, client: &Client) -> Result<BTreeMap<u32, String>, Error> {
+        let mut keys = BTreeMap::new();
+
+        for secret in self.list_secrets(client).await? {
+            match self.spec.secret_layout {
+                PoolSecretLayout::Single if secret.name_any() == self.secret_name() => {
+                    let entries = secret.data.iter().flatten();
+                    for (entry, value) in entries {
+                        let slot = entry.strip_prefix(SLOT_ENTRY_PREFIX);
+                        let Some(slot) = slot.and_then(|slot| slot.parse().ok()) else {
+                            continue;
+                        };
+                        if let Ok(value) = String::from_utf8(value.0.clone()) {
+                            keys.insert(slot, value);
+                        }
+                    }
+                }
+                PoolSecretLayout::PerKey => {
+                    let slot = kube::ResourceExt::labels(&secret).get(SLOT_LABEL);
+                    let Some(slot) = slot.and_then(|slot| slot.parse().ok()) else {
+                        continue;
+                    };
+                    if let Some(value) = read_secret_entry(&secret, "authkey") {
+                        keys.insert(slot, value);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(keys)
+    }
+
+    fn render_secret(&self, name: &str) -> Secret {
+        let owner_ref = self.owner_ref(&()).unwrap_or_default();
+
+        Secret::new(name)
+            .namespace(self.namespace_any())
+            .labels(self.common_labels(name))
+            .owner(owner_ref)
+    }
+
+    fn render_secrets(&self, keys: &BTreeMap<u32, String>) -> Vec<Secret> {
+        let value = |key: &String| ByteString(key.as_bytes().to_vec());
+
+        match self.spec.secret_layout {
+            PoolSecretLayout::Single => {
+                let data = keys
+                    .iter()
+                    .map(|(slot, key)| (format!("{SLOT_ENTRY_PREFIX}{slot}"), value(key)));
+
+                vec![self.render_secret(&self.secret_name()).data(data)]
+            }
+            PoolSecretLayout::PerKey => keys
+                .iter()
+                .map(|(slot, key)| {
+                    let name = format!("{}-{slot}", self.secret_name());
+                    self.render_secret(&name)
+                        .labels([(SLOT_LABEL, slot.to_string())])
+                        .data([("authkey", value(key))])
+                })
+                .collect(),
+        }
+    }
+
+    /// writes the keys to the secrets of the pool and removes secrets that are no longer used,
+    /// for example after the pool was scaled down or the layout changed
+    async fn write_secrets(
+        &self,
+        client: &Client,
+        keys: &BTreeMap<u32, String>,
+    ) -> Result<(), Error> {
+        let secrets = self.render_secrets(keys);
+        let names: Vec<_> = secrets.iter().map(|secret| secret.name_any()).collect();
+
+        for secret in secrets {
+            secret.apply(client).await?;
+        }
+
+        for secret in self.list_secrets(client).await? {
+            if !names.contains(&secret.name_any()) {
+                secret.delete(client).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// whether a key was issued with options that no longer match the spec
+    fn drifted(&self, key: &PoolKeyStatus) -> bool {
+        let mut tags = self.spec.tags.clone();
+        let mut issued_tags = key.tags.clone();
+        tags.sort();
+        issued_tags.sort();
+
+        key.ephemeral != self.spec.ephemeral
+            || tags != issued_tags
+            || key.requested_expiration != self.spec.expiration
+    }
+
+    /// the conditions of the status with the `Ready` condition set
+    fn ready(&self, status: bool, reason: &str, message: impl Into<String>) -> Vec<Condition> {
+        let mut conditions = self
+            .status
+            .as_ref()
+            .map(|status| status.conditions.clone())
+            .unwrap_or_default();
+        let generation = self.meta().generation;
+        set_condition(
+            &mut conditions,
+            "Ready",
+            status,
+            reason,
+            message,
+            generation,
+        );
+        conditions
+    }
+
+    async fn patch_status(&self, client: &Client, status: serde_json::Value) -> Result<(), Error> {
+        let api = Api::<PreauthKeyPool>::namespaced(client.clone(), &self.namespace_any());
+        api.patch_status(
+            &self.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "status": status })),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[kubus(
+    event = Apply,
+    finalizer = "headscale.juliamertz.dev/preauth-key-pool-finalizer",
+    requeue_interval = 60
+)]
+async fn refill_preauth_key_pool(
+    pool: Arc<PreauthKeyPool>,
+    ctx: Arc<Context<State>>,
+) -> Result<(), Error> {
+    let client = ctx.client.clone();
+    let name = pool.name_any();
+
+    let (user, headscale) = pool.resolve(&client).await?;
+    let user_id = user.id().context("user is missing an id")?;
+    let status = pool.status.clone().unwrap_or_default();
+
+    let options = KeyOptions {
+        expiration: &pool.spec.expiration,
+        ephemeral: pool.spec.ephemeral,
+        reusable: false,
+        tags: &pool.spec.tags,
+    };
+
+    // a key may have been created before its id could be recorded
+    if let Some(ref since) = status.issuing_since {
+        let untracked = headscale
+            .expire_untracked_key(&client, &user, since, &options)
+            .await?;
+        if let Some(message) = report_untracked(&client, &*pool, untracked).await {
+            // no keys are added until the untracked ones are expired
+            let conditions = pool.ready(false, "UntrackedKeys", message);
+            return pool
+                .patch_status(&client, json!({ "conditions": conditions }))
+                .await;
+        }
+    }
+
+    let foreign = pool.foreign_secrets(&client).await?;
+    if !foreign.is_empty() {
+        let message = format!(
+            "secrets {} already exist and aren't owned by the pool",
+            foreign.join(", ")
+        );
+        publish_event(
+            &client,
+            &*pool,
+            EventType::Warning,
+            "SecretConflict",
+            message.clone(),
+        )
+        .await;
+        let conditions = pool.ready(false, "SecretConflict", message);
+        return pool
+            .patch_status(&client, json!({ "conditions": conditions }))
+            .await;
+    }
+
+    let stored = pool.read_keys(&client).await?;
+    let issued: BTreeMap<_, _> = headscale
+        .list_preauth_keys(&client, user_id)
+        .await?
+        .into_iter()
+        .map(|key| (key.id, key))
+        .collect();
+    let now = Timestamp::now();
+
+    let mut keys = BTreeMap::new();
+    for key in status.keys {
+        let value = stored
+            .get(&key.slot)
+            .filter(|value| sha256_hex(value) == key.key_hash);
+
+        let reason = match issued.get(&key.id) {
+            None => "key no longer exists",
+            Some(data) if data.used => "key was used",
+            Some(data) if data.expiration <= now => "key expired",
+            Some(_) if value.is_none() => "secret was modified",
+            Some(_) if key.slot >= pool.spec.size => "pool was scaled down",
+            Some(_) if pool.drifted(&key) => "spec changed",
+            Some(_) => {
+                keys.insert(key.slot, (key, value.cloned().unwrap_or_default()));
+                continue;
+            }
+        };
+
+        tracing::info!({ pool = &name, id = key.id, reason }, "removing key from pool");
+
+        // keys that are still valid are expired so they can't be used outside the pool
+        let valid = issued
+            .get(&key.id)
+            .is_some_and(|data| !data.used && data.expiration > now);
+        if valid {
+            let value = value.map(String::as_str);
+            headscale
+                .expire_preauth_key(&client, user_id, key.id, value)
+                .await?;
+        }
+    }
+
+    let statuses = |keys: &BTreeMap<u32, (PoolKeyStatus, String)>| -> Vec<PoolKeyStatus> {
+        keys.values().map(|(status, _)| status.clone()).collect()
+    };
+
+    let free: Vec<_> = (0..pool.spec.size)
+        .filter(|slot| !keys.contains_key(slot))
+        .collect();
+
+    let issuer = HeadscaleRef {
+        name: headscale.name_any(),
+        namespace: headscale.namespace(),
+    };
+
+    if !free.is_empty() {
+        // record the intent to issue keys before anything is created in headscale
+        let patch = json!({
+            "issuingSince": Timestamp::now(),
+            "user": user.status,
+            "headscale": issuer,
+            "keys": statuses(&keys),
+        });
+        pool.patch_status(&client, patch).await?;
+
+        for slot in free {
+            let data = headscale
+                .create_preauth_key(&client, &user, &options)
+                .await?;
+            let status = PoolKeyStatus {
+                slot,
+                id: data.id,
+                key_hash: sha256_hex(&data.key),
+                expiration: data.expiration,
+                created_at: data.created_at,
+                ephemeral: data.ephemeral,
+                tags: data.acl_tags,
+                requested_expiration: pool.spec.expiration.clone(),
+            };
+            keys.insert(slot, (status, data.key));
+
+            // record the id right away, so a retry can expire the key if the secret is never
+            // written, and restart the window in which an untracked key is looked for
+            let patch = json!({ "issuingSince": Timestamp::now(), "keys": statuses(&keys) });
+            pool.patch_status(&client, patch).await?;
+        }
+
+        tracing::info!({ pool = &name, size = pool.spec.size }, "refilled preauth key pool");
+    }
+
+    let values = keys
+        .iter()
+        .map(|(slot, (_, value))| (*slot, value.clone()))
+        .collect();
+    pool.write_secrets(&client, &values).await?;
+
+    let patch = json!({
+        "issuingSince": null,
+        "user": user.status,
+        "headscale": issuer,
+        "available": keys.len(),
+        "keys": statuses(&keys),
+        "conditions": pool.ready(true, "Refilled", "every key of the pool is available"),
+    });
+    pool.patch_status(&client, patch).await?;
+
+    Ok(())
+}
+
+#[kubus(event = Delete, finalizer = "headscale.juliamertz.dev/preauth-key-pool-finalizer")]
+async fn drain_preauth_key_pool(
+    pool: Arc<PreauthKeyPool>,
+    ctx: Arc<Context<State>>,
+) -> Result<(), Error> {
+    let client = ctx.client.clone();
+    let status = pool.status.clone().unwrap_or_default();
+    if status.keys.is_empty() {
+        return Ok(());
+    }
+
+    // keys are expired on the instance that issued them, the user may be gone already
+    let Some(headscale) = pool.issuer(&client, &status).await? else {
+        let note = "headscale instance no longer exists, skipped expiring the keys of the pool";
+        publish_event(
+            &client,
+            &*pool,
+            EventType::Warning,
+            "RevocationSkipped",
+            note.to_string(),
+        )
+        .await;
+        return Ok(());
+    };
+    let user_id = match status.user {
+        Some(ref user) => user.id,
+        None => pool
+            .resolve(&client)
+            .await?
+            .0
+            .id()
+            .context("user is missing an id")?,
+    };
+
+    let stored = pool.read_keys(&client).await?;
+    let issued: BTreeMap<_, _> = headscale
+        .list_preauth_keys(&client, user_id)
+        .await?
+        .into_iter()
+        .map(|key| (key.id, key))
+        .collect();
+    let now = Timestamp::now();
+
+    for key in &status.keys {
+        let valid = issued
+            .get(&key.id)
+            .is_some_and(|data| !data.used && data.expiration > now);
+        if valid {
+            // keys are expired by id when the secret was modified
+            let value = stored
+                .get(&key.slot)
+                .filter(|value| sha256_hex(value) == key.key_hash)
+                .map(String::as_str);
+            headscale
+                .expire_preauth_key(&client, user_id, key.id, value)
+                .await?;
+        }
+    }
+
+    // the secrets are owned by the pool and removed by the garbage collector
+    Ok(())
+}
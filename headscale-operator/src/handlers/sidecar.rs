@@ -0,0 +1,97 @@
+use crate::admission::sidecar::{
+    ANNOTATION_EPHEMERAL_SECRET, ANNOTATION_TAGS, ANNOTATION_USER, LABEL_EPHEMERAL_KEY,
+};
+
+use super::*;
+
+impl PreauthKey {
+    /// single use ephemeral key for a pod with an injected sidecar, owned by the pod so it is
+    /// revoked once the pod is gone
+    fn for_pod(pod: &Pod, secret_name: &str) -> Result<Self, Error> {
+        let annotations = kube::ResourceExt::annotations(pod);
+        let user = annotations
+            .get(ANNOTATION_USER)
+            .ok_or_else(|| anyhow!("missing '{ANNOTATION_USER}' annotation"))?;
+        // keys are only issued for users in the namespace of the pod, otherwise anyone allowed to
+        // create pods could get keys for users they have no access to
+        if user.contains('/') {
+            return Err(anyhow!(
+                "'{ANNOTATION_USER}' must name a user in the namespace of the pod"
+            )
+            .into());
+        }
+        let user = UserRef {
+            name: user.to_string(),
+            namespace: None,
+        };
+        let tags = annotations
+            .get(ANNOTATION_TAGS)
+            .map(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut preauth_key = PreauthKey::new(
+            secret_name,
+            PreauthKeySpec {
+                ephemeral: true,
+                reusable: false,
+                target_secret: Some(secret_name.to_string()),
+                user,
+                tags,
+                ..Default::default()
+            },
+        );
+        preauth_key.metadata.namespace = pod.namespace();
+        preauth_key.metadata.labels =
+            Some([(LABEL_EPHEMERAL_KEY.to_string(), "true".to_string())].into());
+        preauth_key.metadata.owner_references = pod.owner_ref(&()).map(|owner| vec![owner]);
+
+        Ok(preauth_key)
+    }
+}
+
+#[kubus(event = Apply, label_selector = "headscale.juliamertz.dev/tailscale-ephemeral-key=true")]
+pub async fn sync_sidecar_key(pod: Arc<Pod>, ctx: Arc<Context<State>>) -> Result<(), Error> {
+    let client = &ctx.client;
+    let Some(secret_name) =
+        kube::ResourceExt::annotations(pod.as_ref()).get(ANNOTATION_EPHEMERAL_SECRET)
+    else {
+        tracing::warn!(
+            pod = pod.name_any(),
+            "pod is missing ephemeral secret annotation"
+        );
+        return Ok(());
+    };
+
+    let api = Api::<PreauthKey>::namespaced(client.clone(), &pod.namespace_any());
+    let phase = pod
+        .status
+        .as_ref()
+        .and_then(|status| status.phase.as_deref());
+    let terminated = matches!(phase, Some("Succeeded" | "Failed"));
+
+    // pods that ran to completion stick around, revoke their key without waiting for them to be
+    // deleted
+    if terminated || pod.metadata.deletion_timestamp.is_some() {
+        if api.get_opt(secret_name).await?.is_some() {
+            tracing::info!(
+                pod = pod.name_any(),
+                "revoking ephemeral key of terminated pod"
+            );
+            api.delete(secret_name, &Default::default()).await?;
+        }
+
+        return Ok(());
+    }
+
+    PreauthKey::for_pod(&pod, secret_name)?
+        .apply_if_not_exists(client)
+        .await?;
+
+    Ok(())
+}
@@ -13,6 +13,30 @@ impl UserRef {
 
         api.get(&self.name).await
     }
+
+    /// whether this reference, declared in `namespace`, points at the given user
+    pub fn refers_to(&self, user: &User, namespace: &str) -> bool {
+        let target = self.namespace.as_deref().unwrap_or(namespace);
+        self.name == user.name_any() && target == user.namespace_any()
+    }
+}
+
+impl Headscale {
+    /// lists all User resources that reference this headscale instance
+    pub async fn list_user_resources(&self, client: &Client) -> Result<Vec<User>, Error> {
+        let api = Api::<User>::all(client.clone());
+        let users = api.list(&ListParams::default()).await?;
+
+        Ok(users
+            .items
+            .into_iter()
+            .filter(|user| {
+                user.spec
+                    .headscale_ref
+                    .refers_to(self, &user.namespace_any())
+            })
+            .collect())
+    }
 }
 
 impl User {
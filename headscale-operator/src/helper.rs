@@ -1,15 +1,17 @@
 use std::fmt::{Debug, Display};
 use std::ops::Deref;
-use std::sync::LazyLock;
+use std::sync::{LazyLock, OnceLock};
 
 use async_trait::async_trait;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
 use k8s_openapi_ext::resource::Quantity;
 use kube::api::{AttachParams, Execute};
-use kube::{Api, Resource, ResourceExt as _};
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::{Api, Client, Resource, ResourceExt as _};
 use serde::Deserialize;
 use serde::de::DeserializeOwned;
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[derive(Debug, Default, Clone)]
 pub struct CmdBuilder {
@@ -36,6 +38,19 @@ impl CmdBuilder {
         }
     }
 
+    pub fn list_arg(self, name: impl ToString, args: &[impl ToString]) -> Self {
+        if args.is_empty() {
+            return self;
+        }
+
+        let joined = args
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        self.arg(name).arg(joined)
+    }
+
     pub fn bool_arg(self, name: impl ToString, cond: bool) -> Self {
         if cond { self.arg(name) } else { self }
     }
@@ -74,6 +89,20 @@ pub enum ExecError {
 #[async_trait]
 pub trait ExecuteExt {
     async fn exec_with_output<I, T>(&self, name: &str, command: I) -> Result<String, ExecError>
+    where
+        I: IntoIterator<Item = T> + Debug + Send + Sync + 'static,
+        T: Into<String>,
+    {
+        self.exec_with_input(name, command, None).await
+    }
+
+    /// like `exec_with_output`, writing `input` to stdin of the command when given
+    async fn exec_with_input<I, T>(
+        &self,
+        name: &str,
+        command: I,
+        input: Option<Vec<u8>>,
+    ) -> Result<String, ExecError>
     where
         I: IntoIterator<Item = T> + Debug + Send + Sync + 'static,
         T: Into<String>;
@@ -84,18 +113,30 @@ impl<K> ExecuteExt for Api<K>
 where
     K: Resource + Execute + Clone + DeserializeOwned + Send + Sync + 'static,
 {
-    async fn exec_with_output<I, T>(&self, name: &str, command: I) -> Result<String, ExecError>
+    async fn exec_with_input<I, T>(
+        &self,
+        name: &str,
+        command: I,
+        input: Option<Vec<u8>>,
+    ) -> Result<String, ExecError>
     where
         I: IntoIterator<Item = T> + Debug + Send + Sync + 'static,
         T: Into<String>,
     {
         let attach_params = AttachParams::default()
             .container("headscale")
-            .stdin(false)
+            .stdin(input.is_some())
             .stdout(true)
             .stderr(true);
         let mut process = self.exec(name, command, &attach_params).await?;
 
+        if let Some(input) = input {
+            let mut stdin = process.stdin().expect("stdin is attached");
+            stdin.write_all(&input).await?;
+            // dropping stdin closes it, so the command sees the end of the input
+            drop(stdin);
+        }
+
         let Some(output) = process
             .take_status()
             .expect("status has not been taken")
@@ -128,6 +169,97 @@ where
     }
 }
 
+/// hex encoded sha256 digest of the given data
+pub fn sha256_hex(data: impl AsRef<[u8]>) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(data))
+}
+
+/// sets a condition, keeping its transition time when the status didn't change
+pub fn set_condition(
+    conditions: &mut Vec<Condition>,
+    type_: &str,
+    status: bool,
+    reason: &str,
+    message: impl Into<String>,
+    observed_generation: Option<i64>,
+) {
+    let status = Some(status);
+    set_condition_status(
+        conditions,
+        type_,
+        status,
+        reason,
+        message,
+        observed_generation,
+    );
+}
+
+/// sets a condition that may be unknown, `None` for status `Unknown`
+pub fn set_condition_status(
+    conditions: &mut Vec<Condition>,
+    type_: &str,
+    status: Option<bool>,
+    reason: &str,
+    message: impl Into<String>,
+    observed_generation: Option<i64>,
+) {
+    let status = match status {
+        Some(true) => "True",
+        Some(false) => "False",
+        None => "Unknown",
+    }
+    .to_string();
+    let last_transition_time = conditions
+        .iter()
+        .find(|condition| condition.type_ == type_ && condition.status == status)
+        .map(|condition| condition.last_transition_time.clone())
+        .unwrap_or_else(|| Time(k8s_openapi::chrono::Utc::now()));
+
+    conditions.retain(|condition| condition.type_ != type_);
+    conditions.push(Condition {
+        type_: type_.to_string(),
+        status,
+        reason: reason.to_string(),
+        message: message.into(),
+        observed_generation,
+        last_transition_time,
+    });
+}
+
+/// shared so repeated events are aggregated into a series instead of piling up
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+
+/// publishes a kubernetes event about `resource`, failures are logged since events are best effort
+pub async fn publish_event<K>(
+    client: &Client,
+    resource: &K,
+    type_: EventType,
+    reason: &str,
+    note: String,
+) where
+    K: Resource<DynamicType = ()>,
+{
+    let recorder = RECORDER.get_or_init(|| {
+        let reporter = Reporter {
+            controller: env!("CARGO_PKG_NAME").to_string(),
+            instance: std::env::var("POD_NAME").ok(),
+        };
+        Recorder::new(client.clone(), reporter)
+    });
+    let event = Event {
+        type_,
+        reason: reason.to_string(),
+        note: Some(note),
+        action: reason.to_string(),
+        secondary: None,
+    };
+
+    if let Err(err) = recorder.publish(&event, &resource.object_ref(&())).await {
+        tracing::warn!({ reason, error = %err }, "failed to publish event");
+    }
+}
+
 #[derive(Default)]
 pub struct Resources(Vec<(String, Quantity)>);
 
@@ -9,13 +9,17 @@ use kubus::{Operator, print_crds};
 use std::fmt::Debug;
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
+pub(crate) mod acl;
 pub(crate) mod admission;
 pub(crate) mod crds;
+pub(crate) mod explain;
 pub(crate) mod handlers;
 pub(crate) mod helper;
 pub(crate) mod rbac;
@@ -23,9 +27,15 @@ pub(crate) mod rbac;
 use crds::*;
 
 use crate::handlers::User;
+use crate::handlers::group::{remove_group, remove_user_members, resync_user_members, sync_group};
 use crate::handlers::headscale::{cleanup_headscale, deploy_headscale};
-use crate::handlers::policy::{create_acl_policy, delete_acl_policy};
+use crate::handlers::policy::{
+    HostResyncs, confirm_policy_sync, create_acl_policy, delete_acl_policy, release_node_hosts,
+    release_service_hosts, resync_node_hosts, resync_service_hosts, resync_user_groups,
+};
 use crate::handlers::preauth_key::{create_preauth_key, revoke_preauth_key};
+use crate::handlers::preauth_key_pool::{drain_preauth_key_pool, refill_preauth_key_pool};
+use crate::handlers::sidecar::sync_sidecar_key;
 use crate::handlers::user::{create_user, destroy_user};
 
 #[derive(Debug, Error)]
@@ -44,6 +54,10 @@ pub enum Error {
     SerializePatch(#[from] kube::core::admission::SerializePatchError),
     #[error("invalid json pointer: {0}")]
     JsonPtr(#[from] json_patch::jsonptr::ParseError),
+    #[error("yaml error: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("invalid label selector: {0}")]
+    Selector(#[from] kube::core::ParseExpressionError),
 }
 
 #[derive(Parser)]
@@ -61,12 +75,49 @@ enum Command {
         #[arg(long, env = "TLS_CERT_PATH")]
         tls_path: Option<PathBuf>,
 
+        /// seconds a failing deletion is retried before its finalizer is released anyway
+        #[arg(long, env = "FINALIZER_TIMEOUT", default_value_t = 300)]
+        finalizer_timeout: u64,
+
         #[arg(env = "CONFIG_MANAGER_IMAGE")]
         config_manager_image: String,
     },
+    /// inspect the acl policies
+    Policy {
+        #[command(subcommand)]
+        command: PolicyCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum PolicyCommand {
+    /// show whether a source can reach a destination, and the rule that decides it
+    Explain {
+        /// user, group, tag, host or ip the connection originates from
+        src: String,
+
+        /// destination with a single port, such as `tag:web:443`
+        dst: String,
+
+        /// policy manifests to read instead of the policies in the cluster
+        #[arg(short, long = "file")]
+        files: Vec<PathBuf>,
+
+        /// headscale instance whose policies are read from the cluster
+        #[arg(long, conflicts_with = "files", required_unless_present = "files")]
+        headscale: Option<String>,
+
+        /// namespace of the headscale instance, and of policies in manifests without one
+        #[arg(short, long, default_value = "default")]
+        namespace: String,
+    },
 }
 
-pub type State = ();
+#[derive(Clone, Debug)]
+pub struct State {
+    pub finalizer_timeout: Duration,
+    pub host_resyncs: Arc<HostResyncs>,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -78,22 +129,55 @@ async fn main() -> Result<(), Error> {
         .unwrap();
 
     match opts.command {
-        Command::Crd => print_crds![Headscale, Policy, PreauthKey, User],
+        Command::Crd => print_crds![Group, Headscale, Policy, PreauthKey, PreauthKeyPool, User],
+
+        Command::Policy {
+            command:
+                PolicyCommand::Explain {
+                    src,
+                    dst,
+                    files,
+                    headscale,
+                    namespace,
+                },
+        } => explain::explain(&src, &dst, &files, headscale.as_deref(), &namespace).await?,
 
-        Command::Run { tls_path, .. } => {
+        Command::Run {
+            tls_path,
+            finalizer_timeout,
+            ..
+        } => {
             let client = Client::try_default().await.unwrap();
+            let state = State {
+                finalizer_timeout: Duration::from_secs(finalizer_timeout),
+                host_resyncs: Arc::new(HostResyncs::load(&client).await?),
+            };
             let mut operator = Operator::builder()
-                .with_context((client, ()))
+                .with_context((client, state))
                 .handler(create_user)
                 .handler(destroy_user)
                 .handler(deploy_headscale)
                 .handler(cleanup_headscale)
                 .handler(create_acl_policy)
                 .handler(delete_acl_policy)
+                .handler(confirm_policy_sync)
+                .handler(resync_user_groups)
+                .handler(resync_service_hosts)
+                .handler(release_service_hosts)
+                .handler(resync_node_hosts)
+                .handler(release_node_hosts)
+                .handler(sync_group)
+                .handler(remove_group)
+                .handler(resync_user_members)
+                .handler(remove_user_members)
                 .handler(create_preauth_key)
                 .handler(revoke_preauth_key)
+                .handler(refill_preauth_key_pool)
+                .handler(drain_preauth_key_pool)
+                .handler(sync_sidecar_key)
                 .mutator(admission::headscale::mutate)
-                .mutator(admission::sidecar::mutate);
+                .mutator(admission::sidecar::mutate)
+                .validator(admission::policy::validate);
 
             if let Some(tls_path) = tls_path {
                 operator = operator.with_tls_certs(tls_path)
@@ -132,7 +132,12 @@ impl ConfigManagerRbac {
                 api_groups: Some(vec!["".to_string()]),
                 resources: Some(vec!["configmaps".to_string()]),
                 resource_names: Some(vec![acl_configmap_name.to_string()]),
-                verbs: vec!["get".to_string(), "list".to_string(), "watch".to_string()],
+                verbs: vec![
+                    "get".to_string(),
+                    "list".to_string(),
+                    "watch".to_string(),
+                    "patch".to_string(),
+                ],
                 ..Default::default()
             }]),
         };